@@ -1,112 +1,667 @@
 use gnunet_sys::*;
 
 use std::{
+	cell::RefCell,
+	collections::{HashMap, HashSet, VecDeque},
+	error, fmt, mem,
+	pin::Pin,
 	ptr,
-	os::raw::*
+	os::raw::*,
+	rc::Rc,
+	task::{Context, Poll, Waker}
 };
 
-use crate::configuration;
+use futures::Sink;
+
+use crate::closure;
+use crate::configuration::{Configuration, ConfigurationRef};
 use crate::crypto::*;
 use crate::mq;
 
+mod monitor;
+mod port_id;
+mod resilient;
+pub mod rpc;
+mod stream;
+pub use monitor::*;
+pub use port_id::*;
+pub use resilient::*;
+pub use stream::*;
+
+
+
+/// The maximum size of a message on a channel, including its header, like `GNUNET_CONSTANTS_MAX_CADET_MESSAGE_SIZE`.
+/// The service drops larger messages.
+pub const MAX_MESSAGE_SIZE: usize = 65536 - 1024;
+
+/// A connection to the CADET service.
+/// When dropped, the ports and channels that have been created with it are closed and destroyed first, after which none of their callbacks will be called anymore.
+pub struct Handle {
+	inner: *mut GNUNET_CADET_Handle,
+	// The monitoring functions connect to the service by themselves.
+	config: Configuration,
+	registry: Rc<RefCell<Registry>>
+}
 
+/// A channel to another peer.
+/// The channels that are given to callbacks are only borrowed, while the ones returned by `Handle::create_channel`, `Handle::open_channel` and `Listener` are owned and get destroyed when dropped.
+///
+/// Owned channels are a `Stream` of the messages they receive, and a `Sink` of envelopes to send.
+/// Only channels created with `Handle::open_channel` or received from a `Listener` receive messages through the stream, the other ones give them to their message handlers.
+pub struct Channel {
+	inner: *mut GNUNET_CADET_Channel,
+	ownership: Option<ChannelOwnership>
+}
 
-pub struct Handle ( pub(in crate) *mut GNUNET_CADET_Handle );
+/// The closure of a channel, which is also the closure of its message handlers.
+#[repr(C)]
+struct ChannelClosureData {
+	handler_context: mq::HandlerContext,
+	handlers: mq::MessageHandlers,
+	state: Rc<RefCell<ChannelState>>,
+	on_window_change: Option<Box<dyn FnMut(&Channel, usize)>>,
+	on_disconnect: Option<Box<dyn FnMut(&Channel)>>
+}
 
-pub struct Channel ( *mut GNUNET_CADET_Channel );
+#[derive(Debug)]
+pub enum ChannelError {
+	/// The channel has been destroyed, or the other side has disconnected.
+	Disconnected
+}
 
-struct ChannelClosureData<W,D> where
-	W: FnMut(&Channel, usize),
-	D: FnMut(&Channel)
-{
-	on_window_change: W,
-	on_disconnect: D
+/// Information about a channel.
+pub struct ChannelInfo {
+	/// The peer on the other side of the channel.
+	pub peer: PeerIdentity
 }
 
-pub struct Port ( *mut GNUNET_CADET_Port );
+struct ChannelOwnership {
+	_closures: closure::Repeating,
+	state: Rc<RefCell<ChannelState>>,
+	registry: Rc<RefCell<Registry>>,
+	id: usize
+}
+
+/// The calls to the service that an owned channel makes when it is used as a stream or sink.
+/// Tests replace them, as their channels aren't known to the service.
+#[derive(Clone, Copy)]
+struct ChannelFfi {
+	receive_done: unsafe fn( *mut GNUNET_CADET_Channel ),
+	send: fn( &Channel, mq::Envelope )
+}
 
+#[derive(Default)]
+struct ChannelState {
+	disconnected: bool,
+	destroyed: bool,
+	ffi: ChannelFfi,
+	incoming: VecDeque<mq::Message>,
+	receive_waker: Option<Waker>,
+	send_waker: Option<Waker>,
+	unsent: usize,
+	window_size: Option<usize>
+}
+
+/// An open port that yields the channels that connect to it.
+/// The port is closed when dropped.
+pub struct Listener {
+	_port: Port,
+	state: Rc<RefCell<ListenerState>>
+}
+
+#[repr(C)]
+struct ListenerClosureData {
+	handler_context: mq::HandlerContext,
+	handlers: mq::MessageHandlers,
+	message_types: Vec<u16>,
+	state: Rc<RefCell<ListenerState>>,
+	registry: Rc<RefCell<Registry>>
+}
+
+#[derive(Default)]
+struct ListenerState {
+	incoming: VecDeque<(Channel, PeerIdentity)>,
+	waker: Option<Waker>
+}
+
+/// An open port, which is closed when dropped.
+pub struct Port {
+	registry: Rc<RefCell<Registry>>,
+	id: usize,
+	_closures: closure::Repeating
+}
+
+/// The closure of a port, which is also the closure of all channels connecting to it, and of their message handlers.
+#[repr(C)]
 struct PortClosureData<C,W,D> where
 	C: FnMut(&mut Channel, &PeerIdentity),
 	W: FnMut(&Channel, usize),
 	D: FnMut(&Channel)
 {
+	handler_context: mq::HandlerContext,
+	handlers: mq::MessageHandlers,
+	incoming: IncomingChannels,
 	on_connect: C,
-	c: ChannelClosureData<W,D>
+	on_window_change: W,
+	on_disconnect: D
 }
 
-unsafe impl Send for Handle {}
+/// The borrowed channels that connected through a port, and still use its closures.
+type IncomingChannels = Rc<RefCell<HashSet<*mut GNUNET_CADET_Channel>>>;
+
+struct PortEntry {
+	inner: *mut GNUNET_CADET_Port,
+	incoming: IncomingChannels
+}
+
+/// The owned channels and open ports of a handle, that need to be torn down before it disconnects.
+#[derive(Default)]
+struct Registry {
+	next_id: usize,
+	channels: HashMap<usize, (*mut GNUNET_CADET_Channel, Rc<RefCell<ChannelState>>)>,
+	ports: HashMap<usize, PortEntry>
+}
 
 
 
 impl Handle {
 
-	pub fn connect( config: &configuration::Handle ) -> Self {
+	pub fn connect( config: &ConfigurationRef ) -> Self {
 
-		let inner = unsafe { GNUNET_CADET_connect( config.0 ) };
+		let inner = unsafe { GNUNET_CADET_connect( config.as_ptr() ) };
 		assert!(inner != ptr::null_mut(), "null handler");
-		Self ( inner )
+		Self {
+			inner,
+			config: config.to_owned(),
+			registry: Rc::new( RefCell::new( Registry::default() ) )
+		}
 	}
 
-	pub fn create_channel<W,D>( &mut self, destination: &PeerIdentity, port: &HashCode, on_window_change: W, on_disconnect: D, handlers: &mq::MessageHandler ) -> Channel where
-		W: FnMut(&Channel, usize),
-		D: FnMut(&Channel)
+	pub fn create_channel<W,D>( &mut self, destination: &PeerIdentity, port: &PortId, on_window_change: W, on_disconnect: D, handlers: mq::MessageHandlers ) -> Channel where
+		W: FnMut(&Channel, usize) + 'static,
+		D: FnMut(&Channel) + 'static
 	{
-		let cls = Box::into_raw( Box::new(
+		let state = Rc::new( RefCell::new( ChannelState::default() ) );
+		self.create_channel_inner( destination, port, handlers, state, Some( Box::new( on_window_change ) ), Some( Box::new( on_disconnect ) ) )
+	}
+
+	fn create_channel_inner( &mut self,
+		destination: &PeerIdentity,
+		port: &PortId,
+		handlers: mq::MessageHandlers,
+		state: Rc<RefCell<ChannelState>>,
+		on_window_change: Option<Box<dyn FnMut(&Channel, usize)>>,
+		on_disconnect: Option<Box<dyn FnMut(&Channel)>>
+	) -> Channel {
+		// The handlers get copied by the service.
+		let chandlers = handlers.to_array();
+
+		let closures = closure::Repeating::new(
 			ChannelClosureData {
-				on_window_change: Box::new( on_window_change ),
-				on_disconnect: Box::new( on_disconnect )
+				handler_context: handlers.new_context(),
+				handlers,
+				state: state.clone(),
+				on_window_change,
+				on_disconnect
 			}
-		) );
+		);
 
-		let inner = unsafe { GNUNET_CADET_channel_create( self.0,
-			cls as _,
+		let inner = unsafe { GNUNET_CADET_channel_create( self.inner,
+			closures.cls(),
 			&destination.0 as _,
-			&port.0 as _, 
-			Some( ffi_window_change::<W,D> ),
-			Some( ffi_disconnect::<W,D> ),
-			&handlers.0 as _
+			&port.hash().0 as _,
+			Some( ffi_window_change ),
+			Some( ffi_disconnect ),
+			chandlers.as_ptr()
 		) };
 
-		Channel ( inner )
+		Channel::owned( inner, closures, state, &self.registry )
 	}
 
-	pub fn disconnect( self ) {
-		unsafe { GNUNET_CADET_disconnect( self.0 ) };
+	/// Disconnects from the CADET service.
+	/// This is the same as dropping the handle.
+	pub fn disconnect( self ) {}
+
+	/// Opens a port of which the incoming channels are yielded by the returned `Listener`.
+	/// Messages of the given types are received through the stream of those channels.
+	pub fn listen( &mut self, port: &PortId, message_types: &[u16] ) -> Listener {
+
+		// Every channel gets its own handlers, but the handlers that we give to the service need to have the same type.
+		let prototype_state = Rc::new( RefCell::new( ChannelState::default() ) );
+		let handlers = stream_handlers( message_types, &prototype_state );
+		let chandlers = handlers.to_array();
+		let state = Rc::new( RefCell::new( ListenerState::default() ) );
+
+		let closures = closure::Repeating::new(
+			ListenerClosureData {
+				handler_context: handlers.new_context(),
+				handlers,
+				message_types: message_types.to_vec(),
+				state: state.clone(),
+				registry: self.registry.clone()
+			}
+		);
+
+		let inner = unsafe { GNUNET_CADET_open_port( self.inner,
+			&port.hash().0 as _,
+			Some( ffi_listener_connect_handler ),
+			closures.cls(),
+			Some( ffi_window_change ),
+			Some( ffi_disconnect ),
+			chandlers.as_ptr()
+		) };
+
+		Listener {
+			_port: Port::new( inner, Rc::default(), closures, &self.registry ),
+			state
+		}
+	}
+
+	/// Creates a channel of which the messages of the given types are received through its stream.
+	pub fn open_channel( &mut self, destination: &PeerIdentity, port: &PortId, message_types: &[u16] ) -> Channel {
+		let state = Rc::new( RefCell::new( ChannelState::default() ) );
+		let handlers = stream_handlers( message_types, &state );
+
+		self.create_channel_inner( destination, port, handlers, state, None, None )
 	}
 
-	pub fn open_port<C,W,D>( &mut self, port: &HashCode, on_connect: C, on_window_change: W, on_disconnect: D, handlers: &mq::MessageHandler ) -> Port where
-		C: FnMut(&mut Channel, &PeerIdentity),
-		W: FnMut(&Channel, usize),
-		D: FnMut(&Channel)
+	pub fn open_port<C,W,D>( &mut self, port: &PortId, on_connect: C, on_window_change: W, on_disconnect: D, handlers: mq::MessageHandlers ) -> Port where
+		C: FnMut(&mut Channel, &PeerIdentity) + 'static,
+		W: FnMut(&Channel, usize) + 'static,
+		D: FnMut(&Channel) + 'static
 	{
+		// The handlers get copied by the service.
+		let chandlers = handlers.to_array();
+		let incoming = IncomingChannels::default();
 
-		let cls = Box::into_raw( Box::new(
+		let closures = closure::Repeating::new(
 			PortClosureData {
+				handler_context: handlers.new_context(),
+				handlers,
+				incoming: incoming.clone(),
 				on_connect,
-				c: ChannelClosureData {
-					on_window_change,
-					on_disconnect
-				}
+				on_window_change,
+				on_disconnect
 			}
-		) );
+		);
 
-		let inner = unsafe { GNUNET_CADET_open_port( self.0,
-			&port.0 as _,
+		let inner = unsafe { GNUNET_CADET_open_port( self.inner,
+			&port.hash().0 as _,
 			Some( ffi_connect_handler::<C,W,D> ),
-			cls as _,
+			closures.cls(),
 			Some( ffi_port_window_change_handler::<C,W,D> ),
 			Some( ffi_port_disconnect_handler::<C,W,D> ),
-			&handlers.0 as _
+			chandlers.as_ptr()
 		) };
 
-		Port ( inner )
+		Port::new( inner, incoming, closures, &self.registry )
+	}
+}
+
+impl Drop for Handle {
+
+	/// Destroys the remaining channels and closes the remaining ports before disconnecting, because disconnecting would call their callbacks otherwise.
+	fn drop( &mut self ) {
+		let ( channels, ports ) = {
+			let mut registry = self.registry.borrow_mut();
+			( mem::take( &mut registry.channels ), mem::take( &mut registry.ports ) )
+		};
+
+		for ( _, ( inner, state ) ) in channels {
+			let mut state = state.borrow_mut();
+
+			if !state.disconnected && !state.destroyed {
+				state.destroyed = true;
+				unsafe { GNUNET_CADET_channel_destroy( inner ) };
+				state.wake_all();
+			}
+		}
+
+		for ( _, port ) in ports {
+			unsafe { port.close() };
+		}
+
+		unsafe { GNUNET_CADET_disconnect( self.inner ) };
+	}
+}
+
+impl Channel {
+
+	fn borrowed( inner: *const GNUNET_CADET_Channel ) -> Self {
+		Self {
+			inner: inner as _,
+			ownership: None
+		}
+	}
+
+	fn owned( inner: *mut GNUNET_CADET_Channel, closures: closure::Repeating, state: Rc<RefCell<ChannelState>>, registry: &Rc<RefCell<Registry>> ) -> Self {
+		let id = registry.borrow_mut().add_channel( inner, state.clone() );
+
+		Self {
+			inner,
+			ownership: Some( ChannelOwnership {
+				_closures: closures,
+				state,
+				registry: registry.clone(),
+				id
+			} )
+		}
+	}
+
+	/// Panics when an owned channel can not be used anymore.
+	/// Borrowed channels are only given to callbacks, during which they are always valid.
+	fn assert_valid( &self ) {
+		if let Some( ownership ) = &self.ownership {
+			let state = ownership.state.borrow();
+			assert!( !state.disconnected && !state.destroyed, "channel has been destroyed" );
+		}
+	}
+
+	/// Destroys the channel.
+	/// This is the same as dropping it.
+	pub fn destroy( self ) {}
+
+	fn destroy_inner( &mut self ) {
+		if let Some( ownership ) = &self.ownership {
+			let mut state = ownership.state.borrow_mut();
+
+			if !state.disconnected && !state.destroyed {
+				state.destroyed = true;
+				unsafe { GNUNET_CADET_channel_destroy( self.inner ) };
+				state.wake_all();
+			}
+		}
+	}
+
+	/// Returns information about the channel.
+	pub fn get_info( &self ) -> ChannelInfo {
+		self.assert_valid();
+		let info = unsafe { GNUNET_CADET_channel_get_info( self.inner, GNUNET_CADET_ChannelInfoOption_GNUNET_CADET_OPTION_PEER ) };
+		assert!( info != ptr::null(), "no channel info" );
+
+		ChannelInfo {
+			peer: PeerIdentity::from_inner( unsafe { (*info).peer } )
+		}
+	}
+
+	/// Returns the message queue of the channel, through which messages can be sent.
+	pub fn get_mq( &self ) -> mq::Handle {
+		self.assert_valid();
+		mq::Handle::from_inner( unsafe { GNUNET_CADET_get_mq( self.inner ) } )
+	}
+
+	/// Tells the service that the last received message has been handled, so that the next one can be received.
+	/// This needs to be called for every message that is given to the message handlers of a channel.
+	/// Channels that receive messages through their stream do this themselves.
+	pub fn receive_done( &self ) {
+		self.assert_valid();
+		unsafe { GNUNET_CADET_receive_done( self.inner ) };
+	}
+
+	fn state( &self ) -> Result<&Rc<RefCell<ChannelState>>, ChannelError> {
+		match &self.ownership {
+			Some( ownership ) => {
+				let state = ownership.state.borrow();
+				if state.disconnected || state.destroyed {
+					Err( ChannelError::Disconnected )
+				}
+				else {
+					Ok( &ownership.state )
+				}
+			},
+			None => Err( ChannelError::Disconnected )
+		}
+	}
+}
+
+impl Drop for Channel {
+
+	/// Destroys owned channels, unless the other side has done so already.
+	/// The closures are freed after that, when no callbacks can be called anymore.
+	fn drop( &mut self ) {
+		self.destroy_inner();
+
+		if let Some( ownership ) = &self.ownership {
+			ownership.registry.borrow_mut().channels.remove( &ownership.id );
+		}
+	}
+}
+
+impl Sink<mq::Envelope> for Channel {
+	type Error = ChannelError;
+
+	fn poll_ready( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Result<(), ChannelError>> {
+		let mut state = self.state()?.borrow_mut();
+
+		// Wait until the other side allows us to send more.
+		if state.window_size == Some( 0 ) {
+			state.send_waker = Some( cx.waker().clone() );
+			return Poll::Pending
+		}
+		Poll::Ready( Ok(()) )
+	}
+
+	fn start_send( self: Pin<&mut Self>, mut envelope: mq::Envelope ) -> Result<(), ChannelError> {
+		let state = self.state()?.clone();
+		let send = state.borrow().ffi.send;
+
+		{
+			let mut state = state.borrow_mut();
+			state.unsent += 1;
+			if let Some( window_size ) = &mut state.window_size {
+				*window_size = window_size.saturating_sub( 1 );
+			}
+		}
+
+		envelope.notify_sent( move || {
+			let mut state = state.borrow_mut();
+			state.unsent -= 1;
+			if let Some( waker ) = state.send_waker.take() {
+				waker.wake();
+			}
+		} );
+
+		send( &self, envelope );
+		Ok(())
+	}
+
+	fn poll_flush( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Result<(), ChannelError>> {
+		let mut state = self.state()?.borrow_mut();
+
+		if state.unsent > 0 {
+			state.send_waker = Some( cx.waker().clone() );
+			return Poll::Pending
+		}
+		Poll::Ready( Ok(()) )
+	}
+
+	/// Flushes the channel and destroys it.
+	fn poll_close( mut self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Result<(), ChannelError>> {
+		match self.as_mut().poll_flush( cx ) {
+			Poll::Ready( Ok(()) ) => {
+				self.destroy_inner();
+				Poll::Ready( Ok(()) )
+			},
+			other => other
+		}
+	}
+}
+
+impl futures::Stream for Channel {
+	type Item = mq::Message;
+
+	/// Yields the received messages, and ends when the channel has been disconnected.
+	fn poll_next( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Option<mq::Message>> {
+		let ownership = match &self.ownership {
+			Some( ownership ) => ownership,
+			None => return Poll::Ready( None )
+		};
+		let mut state = ownership.state.borrow_mut();
+
+		if let Some( message ) = state.incoming.pop_front() {
+			// Let the service know that we are ready for the next message.
+			// The state is still borrowed, so the validity is checked here instead of by `receive_done`.
+			if !state.disconnected && !state.destroyed {
+				unsafe { (state.ffi.receive_done)( self.inner ) };
+			}
+			return Poll::Ready( Some( message ) )
+		}
+
+		if state.disconnected || state.destroyed {
+			return Poll::Ready( None )
+		}
+
+		state.receive_waker = Some( cx.waker().clone() );
+		Poll::Pending
+	}
+}
+
+impl fmt::Display for ChannelError {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ChannelError::Disconnected => write!(f, "channel disconnected")
+		}
+	}
+}
+
+impl error::Error for ChannelError {}
+
+impl Default for ChannelFfi {
+
+	fn default() -> Self {
+		Self {
+			receive_done: ack_received,
+			send: send_envelope
+		}
+	}
+}
+
+impl ChannelState {
+
+	fn wake_all( &mut self ) {
+		if let Some( waker ) = self.receive_waker.take() {
+			waker.wake();
+		}
+		if let Some( waker ) = self.send_waker.take() {
+			waker.wake();
+		}
+	}
+}
+
+impl futures::Stream for Listener {
+	type Item = (Channel, PeerIdentity);
+
+	/// Yields the channels that connect to the port, together with the peer that created it.
+	/// This never ends.
+	fn poll_next( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Option<Self::Item>> {
+		let mut state = self.state.borrow_mut();
+
+		match state.incoming.pop_front() {
+			Some( incoming ) => Poll::Ready( Some( incoming ) ),
+			None => {
+				state.waker = Some( cx.waker().clone() );
+				Poll::Pending
+			}
+		}
 	}
 }
 
 impl Port {
-	pub fn close( self ) {
-		unsafe { GNUNET_CADET_close_port( self.0 ) };
+
+	fn new( inner: *mut GNUNET_CADET_Port, incoming: IncomingChannels, closures: closure::Repeating, registry: &Rc<RefCell<Registry>> ) -> Self {
+		let id = registry.borrow_mut().add_port( PortEntry {
+			inner,
+			incoming
+		} );
+
+		Self {
+			registry: registry.clone(),
+			id,
+			_closures: closures
+		}
 	}
+
+	/// Closes the port, and destroys the channels that have connected through it.
+	/// This is the same as dropping it.
+	pub fn close( self ) {}
+}
+
+impl Drop for Port {
+
+	/// The closures are freed after closing, when no callbacks can be called anymore.
+	/// If the handle has been dropped already, the port has been closed by it.
+	fn drop( &mut self ) {
+		let entry = self.registry.borrow_mut().ports.remove( &self.id );
+
+		if let Some( entry ) = entry {
+			unsafe { entry.close() };
+		}
+	}
+}
+
+impl PortEntry {
+
+	/// Closing a port leaves the channels that connected through it open, but those use the closures of the port.
+	/// So they are destroyed first.
+	unsafe fn close( self ) {
+		let incoming: Vec<_> = self.incoming.borrow_mut().drain().collect();
+		for channel in incoming {
+			GNUNET_CADET_channel_destroy( channel );
+		}
+
+		GNUNET_CADET_close_port( self.inner );
+	}
+}
+
+impl Registry {
+
+	fn add_channel( &mut self, inner: *mut GNUNET_CADET_Channel, state: Rc<RefCell<ChannelState>> ) -> usize {
+		let id = self.next_id();
+		self.channels.insert( id, ( inner, state ) );
+		id
+	}
+
+	fn add_port( &mut self, entry: PortEntry ) -> usize {
+		let id = self.next_id();
+		self.ports.insert( id, entry );
+		id
+	}
+
+	fn next_id( &mut self ) -> usize {
+		self.next_id += 1;
+		self.next_id
+	}
+}
+
+
+
+/// Tells the service that a message has been taken from the incoming queue of a channel.
+unsafe fn ack_received( channel: *mut GNUNET_CADET_Channel ) {
+	GNUNET_CADET_receive_done( channel );
+}
+
+/// Sends an envelope over a channel of which the validity has been checked.
+fn send_envelope( channel: &Channel, envelope: mq::Envelope ) {
+	channel.get_mq().send( envelope );
+}
+
+/// Creates the handlers that put the messages of the given types in the incoming queue of a channel.
+fn stream_handlers( message_types: &[u16], state: &Rc<RefCell<ChannelState>> ) -> mq::MessageHandlers {
+	message_types.iter().fold( mq::MessageHandlers::new(), |handlers, type_| {
+		let state = state.clone();
+
+		handlers.add( mq::MessageHandler::new_var_sized( *type_,
+			|_| true,
+			move |message| {
+				let mut state = state.borrow_mut();
+				state.incoming.push_back( message.to_owned() );
+				if let Some( waker ) = state.receive_waker.take() {
+					waker.wake();
+				}
+			}
+		) )
+	} )
 }
 
 
@@ -116,34 +671,73 @@ unsafe extern "C" fn ffi_connect_handler<C,W,D>( cls: *mut c_void, _channel: *mu
 	W: FnMut(&Channel, usize),
 	D: FnMut(&Channel)
 {
-	let mut data: Box<PortClosureData<C,W,D>> = Box::from_raw( cls as _ );
-	let mut channel = Channel ( _channel as _ );
+	let data: &mut PortClosureData<C,W,D> = closure::Repeating::get( cls );
+	let mut channel = Channel::borrowed( _channel );
 	let source = PeerIdentity ( *_source );
 
+	data.incoming.borrow_mut().insert( _channel );
+
 	(data.on_connect)( &mut channel, &source );
 
 	cls
 }
 
-unsafe extern "C" fn ffi_window_change<W,D>( cls: *mut c_void, _channel: *const GNUNET_CADET_Channel, window_size: c_int ) where
-	W: FnMut(&Channel, usize),
-	D: FnMut(&Channel)
-{
-	let mut data: Box<ChannelClosureData<W,D>> = Box::from_raw( cls as _ );
-	let channel = Channel ( _channel as _ );
+unsafe extern "C" fn ffi_disconnect( cls: *mut c_void, _channel: *const GNUNET_CADET_Channel ) {
+	let data: &mut ChannelClosureData = closure::Repeating::get( cls );
+	let channel = Channel::borrowed( _channel );
 
-	(data.on_window_change)( &channel, window_size as _ );
+	// The channel is destroyed by the service after this.
+	data.state.borrow_mut().disconnected = true;
+	data.state.borrow_mut().wake_all();
+
+	if let Some( on_disconnect ) = &mut data.on_disconnect {
+		on_disconnect( &channel );
+	}
 }
 
-unsafe extern "C" fn ffi_disconnect<W,D>( cls: *mut c_void, _channel: *const GNUNET_CADET_Channel ) where
-	W: FnMut(&Channel, usize),
-	D: FnMut(&Channel)
-{
+/// Gives every incoming channel its own closure, with handlers that put messages in its own queue.
+unsafe extern "C" fn ffi_listener_connect_handler( cls: *mut c_void, _channel: *mut GNUNET_CADET_Channel, _source: *const GNUNET_PeerIdentity ) -> *mut c_void {
+	let data: &mut ListenerClosureData = closure::Repeating::get( cls );
+	let state = Rc::new( RefCell::new( ChannelState::default() ) );
+	let handlers = stream_handlers( &data.message_types, &state );
+
+	let closures = closure::Repeating::new(
+		ChannelClosureData {
+			handler_context: handlers.new_context(),
+			handlers,
+			state: state.clone(),
+			on_window_change: None,
+			on_disconnect: None
+		}
+	);
+	let channel_cls = closures.cls();
+
+	let channel = Channel::owned( _channel, closures, state, &data.registry );
+
+	let mut listener = data.state.borrow_mut();
+	listener.incoming.push_back( ( channel, PeerIdentity::from_inner( *_source ) ) );
+	if let Some( waker ) = listener.waker.take() {
+		waker.wake();
+	}
 
-	let mut data: Box<ChannelClosureData<W,D>> = Box::from_raw( cls as _ );
-	let channel = Channel ( _channel as _ );
+	channel_cls
+}
 
-	(data.on_disconnect)( &channel );
+unsafe extern "C" fn ffi_window_change( cls: *mut c_void, _channel: *const GNUNET_CADET_Channel, window_size: c_int ) {
+	let data: &mut ChannelClosureData = closure::Repeating::get( cls );
+	let channel = Channel::borrowed( _channel );
+
+	{
+		let mut state = data.state.borrow_mut();
+		state.window_size = Some( window_size as _ );
+		if let Some( waker ) = state.send_waker.take() {
+			waker.wake();
+		}
+	}
+
+	if let Some( on_window_change ) = &mut data.on_window_change {
+		on_window_change( &channel, window_size as _ );
+	}
 }
 
 unsafe extern "C" fn ffi_port_window_change_handler<C,W,D>( cls: *mut c_void, _channel: *const GNUNET_CADET_Channel, window_size: c_int ) where
@@ -151,10 +745,10 @@ unsafe extern "C" fn ffi_port_window_change_handler<C,W,D>( cls: *mut c_void, _c
 	W: FnMut(&Channel, usize),
 	D: FnMut(&Channel)
 {
-	let mut data: Box<PortClosureData<C,W,D>> = Box::from_raw( cls as _ );
-	let channel = Channel ( _channel as _ );
+	let data: &mut PortClosureData<C,W,D> = closure::Repeating::get( cls );
+	let channel = Channel::borrowed( _channel );
 
-	(data.c.on_window_change)( &channel, window_size as _ );
+	(data.on_window_change)( &channel, window_size as _ );
 }
 
 unsafe extern "C" fn ffi_port_disconnect_handler<C,W,D>( cls: *mut c_void, _channel: *const GNUNET_CADET_Channel ) where
@@ -162,9 +756,91 @@ unsafe extern "C" fn ffi_port_disconnect_handler<C,W,D>( cls: *mut c_void, _chan
 	W: FnMut(&Channel, usize),
 	D: FnMut(&Channel)
 {
+	let data: &mut PortClosureData<C,W,D> = closure::Repeating::get( cls );
+	let channel = Channel::borrowed( _channel );
+
+	// The service destroys the channel after this.
+	data.incoming.borrow_mut().remove( &( _channel as *mut _ ) );
+
+	(data.on_disconnect)( &channel );
+}
+
 
-	let mut data: Box<PortClosureData<C,W,D>> = Box::from_raw( cls as _ );
-	let channel = Channel ( _channel as _ );
 
-	(data.c.on_disconnect)( &channel );
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::Cell;
+
+	use futures::{task::noop_waker_ref, Stream};
+
+	thread_local! {
+		static ACKS: Cell<usize> = Cell::new( 0 );
+		static SENT: RefCell<Vec<mq::Message>> = RefCell::new( Vec::new() );
+	}
+
+	/// Counts the acknowledgements instead of sending them to the service.
+	unsafe fn count_ack( _channel: *mut GNUNET_CADET_Channel ) {
+		ACKS.with( |acks| acks.set( acks.get() + 1 ) );
+	}
+
+	/// Keeps the messages instead of sending them to the service.
+	fn keep_sent( _channel: &Channel, envelope: mq::Envelope ) {
+		SENT.with( |sent| sent.borrow_mut().push( envelope.to_message() ) );
+	}
+
+	/// The messages that have been sent on any channel of this thread since the last call.
+	pub(super) fn take_sent() -> Vec<mq::Message> {
+		SENT.with( |sent| sent.borrow_mut().drain(..).collect() )
+	}
+
+	/// An owned channel that isn't known to the service, of which the state can be changed by the test.
+	/// It needs to be marked as disconnected before it is dropped, as it can't be destroyed.
+	pub(super) fn test_channel() -> ( Channel, Rc<RefCell<ChannelState>> ) {
+		let registry = Rc::new( RefCell::new( Registry::default() ) );
+		let state = Rc::new( RefCell::new( ChannelState {
+			ffi: ChannelFfi { receive_done: count_ack, send: keep_sent },
+			..ChannelState::default()
+		} ) );
+		let channel = Channel::owned( ptr::null_mut(), closure::Repeating::new( () ), state.clone(), &registry );
+
+		( channel, state )
+	}
+
+	fn message( type_: u16, payload: &[u8] ) -> mq::Message {
+		mq::Message { type_, payload: payload.to_vec() }
+	}
+
+	#[test]
+	fn stream_receives_every_message() {
+		let ( mut channel, state ) = test_channel();
+		let mut cx = Context::from_waker( noop_waker_ref() );
+
+		state.borrow_mut().incoming.push_back( message( 1, b"first" ) );
+		state.borrow_mut().incoming.push_back( message( 2, b"second" ) );
+
+		assert_eq!( Pin::new( &mut channel ).poll_next( &mut cx ), Poll::Ready( Some( message( 1, b"first" ) ) ) );
+		assert_eq!( Pin::new( &mut channel ).poll_next( &mut cx ), Poll::Ready( Some( message( 2, b"second" ) ) ) );
+		assert_eq!( ACKS.with( Cell::get ), 2 );
+
+		assert_eq!( Pin::new( &mut channel ).poll_next( &mut cx ), Poll::Pending );
+		assert!( state.borrow().receive_waker.is_some() );
+
+		// The other side disconnecting ends the stream, without the channel being destroyed by us.
+		state.borrow_mut().disconnected = true;
+		assert_eq!( Pin::new( &mut channel ).poll_next( &mut cx ), Poll::Ready( None ) );
+	}
+
+	#[test]
+	fn stream_yields_queued_messages_after_disconnect() {
+		let ( mut channel, state ) = test_channel();
+		let mut cx = Context::from_waker( noop_waker_ref() );
+
+		state.borrow_mut().incoming.push_back( message( 1, b"last" ) );
+		state.borrow_mut().disconnected = true;
+
+		assert_eq!( Pin::new( &mut channel ).poll_next( &mut cx ), Poll::Ready( Some( message( 1, b"last" ) ) ) );
+		assert_eq!( Pin::new( &mut channel ).poll_next( &mut cx ), Poll::Ready( None ) );
+		assert_eq!( ACKS.with( Cell::get ), 0 );
+	}
+}
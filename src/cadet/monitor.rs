@@ -0,0 +1,321 @@
+use gnunet_sys::*;
+
+use std::{
+	cell::RefCell,
+	collections::VecDeque,
+	os::raw::*,
+	pin::Pin,
+	ptr, slice,
+	rc::Rc,
+	task::{Context, Poll, Waker}
+};
+
+use crate::closure;
+use crate::crypto::PeerIdentity;
+use super::Handle;
+
+
+
+/// A stream of records that the CADET service lists.
+/// The listing is cancelled when dropped before it has completed.
+pub struct Listing<T> {
+	inner: *mut c_void,
+	cancel: unsafe fn( *mut c_void ),
+	closure: closure::Once,
+	state: SharedState<T>
+}
+
+struct ListingState<T> {
+	records: VecDeque<T>,
+	complete: bool,
+	waker: Option<Waker>
+}
+
+type SharedState<T> = Rc<RefCell<ListingState<T>>>;
+
+/// A path through the overlay to a peer.
+#[derive(Clone)]
+pub struct PathInfo {
+	/// The peer that the path leads to.
+	pub peer: PeerIdentity,
+	/// The peers of the path, starting with ourselves.
+	pub path: Vec<PeerIdentity>,
+	/// The offset of `peer` within `path`.
+	pub target_offset: u32
+}
+
+/// A peer that the CADET service knows about.
+#[derive(Clone)]
+pub struct PeerInfo {
+	pub peer: PeerIdentity,
+	/// Whether we have a tunnel to the peer.
+	pub have_tunnel: bool,
+	/// The number of known paths to the peer.
+	pub paths: u32,
+	/// The length of the shortest known path to the peer.
+	pub best_path_length: u32
+}
+
+/// A tunnel to another peer, over which channels are multiplexed.
+#[derive(Clone)]
+pub struct TunnelInfo {
+	pub peer: PeerIdentity,
+	/// The number of channels in the tunnel.
+	pub channels: u32,
+	/// The number of connections that carry the tunnel.
+	pub connections: u32,
+	pub encryption_state: EncryptionState,
+	pub connectivity_state: ConnectivityState
+}
+
+/// The connectivity state of a tunnel, like the service's tunnel `cstate`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectivityState {
+	/// No path to the peer is known yet.
+	New,
+	/// Looking for a path to the peer.
+	Searching,
+	/// A connection is being established.
+	Waiting,
+	Ready,
+	ShuttingDown,
+	/// A state that this version doesn't know about.
+	Unknown( u16 )
+}
+
+/// The state of the key exchange of a tunnel, like the service's `CadetTunnelEState`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionState {
+	Uninitialized,
+	/// Our key has been sent.
+	KeySent,
+	/// The key of the other peer has been received.
+	KeyReceived,
+	/// Both keys have been exchanged.
+	KeySentAndReceived,
+	/// Our authentication has been sent.
+	AuthSent,
+	/// The keys have been exchanged and authenticated.
+	Ok,
+	/// A state that this version doesn't know about.
+	Unknown( u16 )
+}
+
+
+
+impl ConnectivityState {
+
+	fn from_inner( state: u16 ) -> Self {
+		match state {
+			0 => ConnectivityState::New,
+			1 => ConnectivityState::Searching,
+			2 => ConnectivityState::Waiting,
+			3 => ConnectivityState::Ready,
+			4 => ConnectivityState::ShuttingDown,
+			other => ConnectivityState::Unknown( other )
+		}
+	}
+}
+
+impl EncryptionState {
+
+	fn from_inner( state: u16 ) -> Self {
+		match state {
+			0 => EncryptionState::Uninitialized,
+			1 => EncryptionState::KeySent,
+			2 => EncryptionState::KeyReceived,
+			3 => EncryptionState::KeySentAndReceived,
+			4 => EncryptionState::AuthSent,
+			5 => EncryptionState::Ok,
+			other => EncryptionState::Unknown( other )
+		}
+	}
+}
+
+impl Handle {
+
+	/// Lists the paths to the given peer.
+	pub fn path_to( &self, peer: &PeerIdentity ) -> Listing<PathInfo> {
+		let state = Rc::new( RefCell::new( ListingState::new() ) );
+		let closure = closure::Once::new( state.clone() );
+
+		let inner = unsafe { GNUNET_CADET_get_path(
+			self.config.as_ptr(),
+			&peer.0 as _,
+			Some( ffi_path_callback ),
+			closure.cls()
+		) };
+
+		Listing::new( inner as _, cancel_get_path, closure, state )
+	}
+
+	/// Lists all peers that the service knows about.
+	pub fn peers( &self ) -> Listing<PeerInfo> {
+		let state = Rc::new( RefCell::new( ListingState::new() ) );
+		let closure = closure::Once::new( state.clone() );
+
+		let inner = unsafe { GNUNET_CADET_list_peers(
+			self.config.as_ptr(),
+			Some( ffi_peers_callback ),
+			closure.cls()
+		) };
+
+		Listing::new( inner as _, cancel_list_peers, closure, state )
+	}
+
+	/// Lists all tunnels that we have with other peers.
+	pub fn tunnels( &self ) -> Listing<TunnelInfo> {
+		let state = Rc::new( RefCell::new( ListingState::new() ) );
+		let closure = closure::Once::new( state.clone() );
+
+		let inner = unsafe { GNUNET_CADET_list_tunnels(
+			self.config.as_ptr(),
+			Some( ffi_tunnels_callback ),
+			closure.cls()
+		) };
+
+		Listing::new( inner as _, cancel_list_tunnels, closure, state )
+	}
+}
+
+impl<T> Listing<T> {
+
+	fn new( inner: *mut c_void, cancel: unsafe fn( *mut c_void ), closure: closure::Once, state: SharedState<T> ) -> Self {
+		assert!( inner != ptr::null_mut(), "unable to start listing" );
+
+		Self {
+			inner,
+			cancel,
+			closure,
+			state
+		}
+	}
+}
+
+impl<T> Drop for Listing<T> {
+
+	fn drop( &mut self ) {
+		if self.closure.is_pending() {
+			unsafe {
+				(self.cancel)( self.inner );
+				self.closure.cancel();
+			}
+		}
+	}
+}
+
+impl<T> futures::Stream for Listing<T> {
+	type Item = T;
+
+	fn poll_next( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Option<T>> {
+		let mut state = self.state.borrow_mut();
+
+		if let Some( record ) = state.records.pop_front() {
+			Poll::Ready( Some( record ) )
+		}
+		else if state.complete {
+			Poll::Ready( None )
+		}
+		else {
+			state.waker = Some( cx.waker().clone() );
+			Poll::Pending
+		}
+	}
+}
+
+impl<T> ListingState<T> {
+
+	fn new() -> Self {
+		Self {
+			records: VecDeque::new(),
+			complete: false,
+			waker: None
+		}
+	}
+
+	fn wake( &mut self ) {
+		if let Some( waker ) = self.waker.take() {
+			waker.wake();
+		}
+	}
+}
+
+impl PathInfo {
+
+	/// The number of hops to the peer.
+	pub fn length( &self ) -> usize {
+		self.target_offset as _
+	}
+}
+
+
+
+unsafe fn cancel_get_path( inner: *mut c_void ) {
+	GNUNET_CADET_get_path_cancel( inner as _ );
+}
+
+unsafe fn cancel_list_peers( inner: *mut c_void ) {
+	GNUNET_CADET_list_peers_cancel( inner as _ );
+}
+
+unsafe fn cancel_list_tunnels( inner: *mut c_void ) {
+	GNUNET_CADET_list_tunnels_cancel( inner as _ );
+}
+
+/// Adds a record to the listing, or completes it when there is none.
+/// The service signals the end of a listing with a NULL record.
+unsafe fn handle_record<T>( cls: *mut c_void, record: Option<T> ) {
+	match record {
+		Some( record ) => {
+			closure::Once::borrow( cls, |state: &mut SharedState<T>| {
+				let mut state = state.borrow_mut();
+				state.records.push_back( record );
+				state.wake();
+			} );
+		},
+		None => if let Some( state ) = closure::Once::take::<SharedState<T>>( cls ) {
+			let mut state = state.borrow_mut();
+			state.complete = true;
+			state.wake();
+		}
+	}
+}
+
+
+
+unsafe extern "C" fn ffi_path_callback( cls: *mut c_void, ppd: *const GNUNET_CADET_PeerPathDetail ) {
+	let record = ppd.as_ref().map( |ppd| PathInfo {
+		peer: PeerIdentity::from_inner( ppd.peer ),
+		path: if ppd.path_length == 0 { Vec::new() } else {
+			slice::from_raw_parts( ppd.path, ppd.path_length as _ ).iter()
+				.map( |p| PeerIdentity::from_inner( *p ) )
+				.collect()
+		},
+		target_offset: ppd.target_offset as _
+	} );
+
+	handle_record( cls, record );
+}
+
+unsafe extern "C" fn ffi_peers_callback( cls: *mut c_void, ple: *const GNUNET_CADET_PeerListEntry ) {
+	let record = ple.as_ref().map( |ple| PeerInfo {
+		peer: PeerIdentity::from_inner( ple.peer ),
+		have_tunnel: ple.have_tunnel != 0,
+		paths: ple.n_paths as _,
+		best_path_length: ple.best_path_length as _
+	} );
+
+	handle_record( cls, record );
+}
+
+unsafe extern "C" fn ffi_tunnels_callback( cls: *mut c_void, td: *const GNUNET_CADET_TunnelDetails ) {
+	let record = td.as_ref().map( |td| TunnelInfo {
+		peer: PeerIdentity::from_inner( td.peer ),
+		channels: td.channels as _,
+		connections: td.connections as _,
+		encryption_state: EncryptionState::from_inner( td.estate as _ ),
+		connectivity_state: ConnectivityState::from_inner( td.cstate as _ )
+	} );
+
+	handle_record( cls, record );
+}
@@ -0,0 +1,218 @@
+use std::{
+	convert::Infallible,
+	error, fmt,
+	hash::{Hash, Hasher},
+	ops::RangeInclusive,
+	str::FromStr
+};
+
+use crate::crypto::HashCode;
+
+
+
+/// Identifies a port, like a number does for TCP.
+///
+/// Ports are the hash of a name that the application chooses, which is how `gnunet-cadet` derives them from its port argument.
+/// So a port opened with `PortId::from_name( "my-app" )` can be reached with `gnunet-cadet <peer> my-app`.
+///
+/// Ports are shown as their name, and like `gnunet-cadet`, any parsed string is taken as a port name.
+/// Ports of which the name is unknown are shown as their hash, which `to_hash_string` and `from_hash_str` convert back and forth for every port.
+#[derive(Clone)]
+pub struct PortId {
+	hash: HashCode,
+	name: Option<String>
+}
+
+/// The hash given to `PortId::from_hash_str` is not properly encoded.
+#[derive(Clone, Debug)]
+pub struct InvalidPortIdError;
+
+
+
+impl PortId {
+
+	/// The port with the hash of the given name.
+	pub fn from_name( name: &str ) -> Self {
+		Self {
+			hash: HashCode::generate( name.as_bytes() ),
+			name: Some( name.to_owned() )
+		}
+	}
+
+	/// The port with the given hash, of which the name is unknown.
+	pub fn from_hash( hash: HashCode ) -> Self {
+		Self {
+			hash,
+			name: None
+		}
+	}
+
+	/// Parses the hash of a port, as given by `to_hash_string`.
+	pub fn from_hash_str( string: &str ) -> Result<Self, InvalidPortIdError> {
+		HashCode::try_from_string( string ).map( Self::from_hash ).ok_or( InvalidPortIdError )
+	}
+
+	pub fn hash( &self ) -> &HashCode {
+		&self.hash
+	}
+
+	/// The name that the port has been derived from, if it is known.
+	pub fn name( &self ) -> Option<&str> {
+		self.name.as_ref().map( |n| n.as_str() )
+	}
+
+	/// The hash of the port as a string, which `from_hash_str` parses back to the same port.
+	pub fn to_hash_string( &self ) -> String {
+		self.hash.to_string()
+	}
+
+	/// The port of the given version of a protocol, which is named `<protocol>/v<version>`.
+	pub fn versioned( protocol: &str, version: u32 ) -> Self {
+		Self::from_name( &format!( "{}/v{}", protocol, version ) )
+	}
+
+	/// The ports of all given versions of a protocol, newest first.
+	///
+	/// A server opens all of them, while a client tries them in order until a channel stays connected.
+	pub fn versions( protocol: &str, versions: RangeInclusive<u32> ) -> Vec<Self> {
+		versions.rev().map( |v| Self::versioned( protocol, v ) ).collect()
+	}
+}
+
+impl AsRef<HashCode> for PortId {
+
+	fn as_ref( &self ) -> &HashCode {
+		&self.hash
+	}
+}
+
+impl fmt::Debug for PortId {
+
+	fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+		write!( f, "PortId({})", self )
+	}
+}
+
+impl fmt::Display for PortId {
+
+	/// Shows the name of the port, or its hash when the name is unknown.
+	fn fmt( &self, f: &mut fmt::Formatter<'_> ) -> fmt::Result {
+		match &self.name {
+			Some( name ) => write!( f, "{}", name ),
+			None => write!( f, "{}", self.to_hash_string() )
+		}
+	}
+}
+
+impl Eq for PortId {}
+
+impl From<HashCode> for PortId {
+
+	fn from( hash: HashCode ) -> Self {
+		Self::from_hash( hash )
+	}
+}
+
+impl FromStr for PortId {
+	type Err = Infallible;
+
+	/// Like `gnunet-cadet`, any string is a port name, so this is the same as `from_name`.
+	fn from_str( string: &str ) -> Result<Self, Infallible> {
+		Ok( Self::from_name( string ) )
+	}
+}
+
+impl fmt::Display for InvalidPortIdError {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "invalid port hash")
+	}
+}
+
+impl error::Error for InvalidPortIdError {}
+
+impl Hash for PortId {
+
+	fn hash<H: Hasher>( &self, state: &mut H ) {
+		self.hash.raw_data().hash( state );
+	}
+}
+
+impl PartialEq for PortId {
+
+	/// Ports are equal when their hashes are, whether their names are known or not.
+	fn eq( &self, other: &Self ) -> bool {
+		self.hash.raw_data() == other.hash.raw_data()
+	}
+}
+
+
+
+/// Returns the newest version of a protocol that both sides support.
+pub fn negotiate_version( ours: RangeInclusive<u32>, theirs: RangeInclusive<u32> ) -> Option<u32> {
+	let newest = *ours.end().min( theirs.end() );
+	let oldest = *ours.start().max( theirs.start() );
+
+	if oldest <= newest {
+		Some( newest )
+	}
+	else {
+		None
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parsing_is_the_same_as_from_name() {
+		for name in &[ "my-app", "my-app/v2", "", "hash:", "name:x" ] {
+			let port: PortId = name.parse().unwrap();
+
+			assert_eq!( port, PortId::from_name( name ) );
+			assert_eq!( port.name(), Some( *name ) );
+		}
+	}
+
+	#[test]
+	fn named_ports_are_shown_as_their_name() {
+		let port = PortId::from_name( "my-app" );
+
+		assert_eq!( port.to_string(), "my-app" );
+		assert_eq!( port.to_string().parse::<PortId>().unwrap(), port );
+	}
+
+	#[test]
+	fn hashes_round_trip() {
+		let named = PortId::from_name( "my-app" );
+		let unknown = PortId::from_hash( HashCode::generate( b"unknown" ) );
+
+		for port in &[ named, unknown ] {
+			let parsed = PortId::from_hash_str( &port.to_hash_string() ).unwrap();
+
+			assert_eq!( &parsed, port );
+			assert_eq!( parsed.name(), None );
+		}
+	}
+
+	#[test]
+	fn unknown_names_are_shown_as_their_hash() {
+		let port = PortId::from_hash( HashCode::generate( b"unknown" ) );
+
+		assert_eq!( port.to_string(), port.to_hash_string() );
+	}
+
+	#[test]
+	fn malformed_hashes_are_rejected() {
+		assert!( PortId::from_hash_str( "not-a-hash" ).is_err() );
+	}
+
+	#[test]
+	fn newest_common_version_is_negotiated() {
+		assert_eq!( negotiate_version( 1..=3, 2..=5 ), Some( 3 ) );
+		assert_eq!( negotiate_version( 1..=2, 3..=4 ), None );
+	}
+}
@@ -0,0 +1,682 @@
+use gnunet_sys::*;
+
+use std::{
+	cell::RefCell,
+	cmp,
+	collections::{HashMap, VecDeque},
+	convert::TryInto,
+	future::Future,
+	pin::Pin,
+	rc::{Rc, Weak},
+	task::{Context, Poll, Waker},
+	time::Duration
+};
+
+use futures::{Sink, Stream};
+
+use crate::crypto::PeerIdentity;
+use crate::mq;
+use crate::scheduler;
+use super::{Channel, ChannelError, Handle, Listener, PortId};
+
+
+
+/// The message type that carries a message of the application, together with its sequence number.
+pub const DATA_MESSAGE_TYPE: u16 = 0xFF03;
+/// The message type that acknowledges all messages up to a sequence number.
+pub const ACK_MESSAGE_TYPE: u16 = 0xFF04;
+/// The message type that is sent first on every channel of a session, to resume it.
+pub const RESUME_MESSAGE_TYPE: u16 = 0xFF05;
+
+const MESSAGE_TYPES: [u16; 3] = [DATA_MESSAGE_TYPE, ACK_MESSAGE_TYPE, RESUME_MESSAGE_TYPE];
+
+/// The state of the channel underneath a `ResilientChannel`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+	Connected,
+	/// The channel has been disconnected, and the other side needs to reconnect.
+	Disconnected,
+	/// The channel has been disconnected, and will be recreated after a delay.
+	/// The attempt is the number of times this has happened since messages got through.
+	Reconnecting { attempt: u32 },
+	/// The channel has been closed by us.
+	Closed
+}
+
+/// A stream of the state changes of a `ResilientChannel`.
+pub struct Events {
+	shared: Rc<RefCell<Shared>>
+}
+
+/// A channel that survives disconnects.
+///
+/// Every message gets a sequence number, and is kept until the other side acknowledges it.
+/// When the channel gets disconnected, the side that created it recreates it with exponential backoff, and both sides send the messages again that have not been acknowledged.
+/// Duplicates are discarded, so every message is received exactly once, in order.
+///
+/// Other than with `Channel`, sending doesn't fail while disconnected, the messages are kept until the channel is back.
+/// Flushing waits until every message has been acknowledged.
+///
+/// The channel only makes progress while it is polled, as a stream, as a sink or through its events.
+/// Nothing else handles acknowledgements, notices disconnects or reconnects, so a task needs to keep polling it, even when it has nothing to send or receive.
+pub struct ResilientChannel {
+	shared: Rc<RefCell<Shared>>
+}
+
+/// Yields the resilient channels of the peers that connect to a port.
+/// Channels that resume a session are given to the `ResilientChannel` that is already there.
+/// A session can only be resumed by the peer that started it, channels of other peers that try to are dropped.
+pub struct ResilientListener {
+	listener: Listener,
+	// Channels that haven't told which session they belong to yet.
+	pending: Vec<(Channel, PeerIdentity)>,
+	sessions: HashMap<u64, (PeerIdentity, Weak<RefCell<Shared>>)>
+}
+
+struct Backoff {
+	initial: Duration,
+	max: Duration,
+	attempt: u32
+}
+
+struct Shared {
+	session: u64,
+	channel: Option<Channel>,
+	// Only the side that creates the channel reconnects.
+	connect: Option<Box<dyn FnMut() -> Channel>>,
+	backoff: Backoff,
+	reconnect_delay: Option<scheduler::Delay>,
+	state: ConnectionState,
+	events: VecDeque<ConnectionState>,
+	next_sequence: u64,
+	unacknowledged: VecDeque<(u64, mq::Message)>,
+	received: u64,
+	incoming: VecDeque<mq::Message>,
+	receive_waker: Option<Waker>,
+	send_waker: Option<Waker>,
+	event_waker: Option<Waker>
+}
+
+
+
+impl Backoff {
+
+	fn next( &mut self ) -> Duration {
+		let delay = self.initial.checked_mul( 1 << cmp::min( self.attempt, 16 ) ).unwrap_or( self.max );
+		self.attempt += 1;
+		cmp::min( delay, self.max )
+	}
+}
+
+impl Drop for Events {
+
+	/// Another poller needs to take over waiting on the channel.
+	fn drop( &mut self ) {
+		self.shared.borrow_mut().wake_all();
+	}
+}
+
+impl Stream for Events {
+	type Item = ConnectionState;
+
+	/// Ends after the channel has been closed.
+	fn poll_next( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Option<ConnectionState>> {
+		let mut shared = self.shared.borrow_mut();
+		shared.poll_channel( cx );
+
+		if let Some( state ) = shared.events.pop_front() {
+			Poll::Ready( Some( state ) )
+		}
+		else if shared.state == ConnectionState::Closed {
+			Poll::Ready( None )
+		}
+		else {
+			shared.event_waker = Some( cx.waker().clone() );
+			Poll::Pending
+		}
+	}
+}
+
+impl ResilientChannel {
+
+	/// Creates the channel with `connect`, and recreates it with it whenever it gets disconnected.
+	/// The channels need to receive the message types of this module through their stream, like the ones of `Handle::open_channel` do.
+	pub fn connect<F>( connect: F ) -> Self where
+		F: FnMut() -> Channel + 'static
+	{
+		let session = unsafe { GNUNET_CRYPTO_random_u64( GNUNET_CRYPTO_Quality_GNUNET_CRYPTO_QUALITY_NONCE, u64::MAX ) };
+		let mut shared = Shared::new( session, Some( Box::new( connect ) ) );
+
+		let channel = (shared.connect.as_mut().unwrap())();
+		shared.attach( channel );
+
+		Self {
+			shared: Rc::new( RefCell::new( shared ) )
+		}
+	}
+
+	/// The state changes of the channel, starting with the ones that no stream has taken yet, like becoming connected when it has been created.
+	/// All returned streams take from the same queue.
+	pub fn events( &self ) -> Events {
+		Events {
+			shared: self.shared.clone()
+		}
+	}
+
+	/// Creates channels to the given peer and port.
+	pub fn open( handle: Rc<RefCell<Handle>>, destination: PeerIdentity, port: PortId ) -> Self {
+		Self::connect( move || handle.borrow_mut().open_channel( &destination, &port, &MESSAGE_TYPES ) )
+	}
+
+	/// Sets the delay before the first reconnection attempt, which doubles with every attempt up to `max`.
+	/// The default is one second, up to a minute.
+	pub fn set_backoff( &mut self, initial: Duration, max: Duration ) {
+		let mut shared = self.shared.borrow_mut();
+		shared.backoff.initial = initial;
+		shared.backoff.max = max;
+	}
+
+	pub fn state( &self ) -> ConnectionState {
+		self.shared.borrow().state
+	}
+
+	/// The number of sent messages that have not been acknowledged yet.
+	pub fn unacknowledged( &self ) -> usize {
+		self.shared.borrow().unacknowledged.len()
+	}
+}
+
+impl Sink<mq::Message> for ResilientChannel {
+	type Error = ChannelError;
+
+	fn poll_ready( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Result<(), ChannelError>> {
+		let mut shared = self.shared.borrow_mut();
+		shared.poll_channel( cx );
+
+		if shared.state == ConnectionState::Closed {
+			return Poll::Ready( Err( ChannelError::Disconnected ) )
+		}
+
+		// Respect the window of the channel, but keep the messages while disconnected.
+		let ready = match &mut shared.channel {
+			Some( channel ) => Pin::new( channel ).poll_ready( cx ).is_ready(),
+			None => true
+		};
+		if ready {
+			Poll::Ready( Ok(()) )
+		}
+		else {
+			shared.send_waker = Some( cx.waker().clone() );
+			Poll::Pending
+		}
+	}
+
+	fn start_send( self: Pin<&mut Self>, message: mq::Message ) -> Result<(), ChannelError> {
+		let mut shared = self.shared.borrow_mut();
+
+		if shared.state == ConnectionState::Closed {
+			return Err( ChannelError::Disconnected )
+		}
+
+		shared.next_sequence += 1;
+		let sequence = shared.next_sequence;
+		let envelope = data_envelope( sequence, &message );
+		shared.unacknowledged.push_back( ( sequence, message ) );
+		shared.send_frame( envelope );
+		Ok(())
+	}
+
+	/// Waits until all messages have been acknowledged.
+	fn poll_flush( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Result<(), ChannelError>> {
+		let mut shared = self.shared.borrow_mut();
+		shared.poll_channel( cx );
+
+		if shared.unacknowledged.is_empty() {
+			Poll::Ready( Ok(()) )
+		}
+		else if shared.state == ConnectionState::Closed {
+			Poll::Ready( Err( ChannelError::Disconnected ) )
+		}
+		else {
+			shared.send_waker = Some( cx.waker().clone() );
+			Poll::Pending
+		}
+	}
+
+	/// Waits until all messages have been acknowledged, and destroys the channel.
+	fn poll_close( mut self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Result<(), ChannelError>> {
+		match self.as_mut().poll_flush( cx ) {
+			Poll::Ready( Ok(()) ) => {
+				let mut shared = self.shared.borrow_mut();
+				shared.channel = None;
+				shared.reconnect_delay = None;
+				shared.set_state( ConnectionState::Closed );
+				Poll::Ready( Ok(()) )
+			},
+			other => other
+		}
+	}
+}
+
+impl Stream for ResilientChannel {
+	type Item = mq::Message;
+
+	/// Yields the messages of the other side, with their original message types.
+	/// Ends after the channel has been closed.
+	fn poll_next( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Option<mq::Message>> {
+		let mut shared = self.shared.borrow_mut();
+		shared.poll_channel( cx );
+
+		if let Some( message ) = shared.incoming.pop_front() {
+			Poll::Ready( Some( message ) )
+		}
+		else if shared.state == ConnectionState::Closed {
+			Poll::Ready( None )
+		}
+		else {
+			shared.receive_waker = Some( cx.waker().clone() );
+			Poll::Pending
+		}
+	}
+}
+
+impl ResilientListener {
+
+	/// Opens the port.
+	pub fn new( handle: &mut Handle, port: &PortId ) -> Self {
+		Self {
+			listener: handle.listen( port, &MESSAGE_TYPES ),
+			pending: Vec::new(),
+			sessions: HashMap::new()
+		}
+	}
+}
+
+impl Stream for ResilientListener {
+	type Item = (ResilientChannel, PeerIdentity);
+
+	/// Yields the channels of new sessions.
+	/// This never ends.
+	fn poll_next( mut self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Option<Self::Item>> {
+		let this = &mut *self;
+
+		while let Poll::Ready( Some( incoming ) ) = Pin::new( &mut this.listener ).poll_next( cx ) {
+			this.pending.push( incoming );
+		}
+
+		let mut i = 0;
+		while i < this.pending.len() {
+			let message = match Pin::new( &mut this.pending[i].0 ).poll_next( cx ) {
+				Poll::Pending => {
+					i += 1;
+					continue
+				},
+				Poll::Ready( message ) => message
+			};
+			let ( channel, peer ) = this.pending.swap_remove( i );
+
+			// Channels that don't start with a resume message are dropped.
+			if let Some( ( session, received ) ) = message.as_ref().and_then( parse_resume ) {
+				let existing = this.sessions.get( &session ).and_then( |( owner, shared )| Some( ( owner.clone(), shared.upgrade()? ) ) );
+
+				if let Some( ( owner, shared ) ) = existing {
+					// Another peer can't take over the session, its channel is dropped.
+					if owner == peer {
+						shared.borrow_mut().resume( channel, received );
+					}
+				}
+				else {
+					let mut shared = Shared::new( session, None );
+					shared.resume( channel, received );
+
+					let shared = Rc::new( RefCell::new( shared ) );
+					this.sessions.insert( session, ( peer.clone(), Rc::downgrade( &shared ) ) );
+					return Poll::Ready( Some( ( ResilientChannel { shared }, peer ) ) )
+				}
+			}
+		}
+
+		this.sessions.retain( |_, ( _, shared )| shared.strong_count() > 0 );
+		Poll::Pending
+	}
+}
+
+impl Shared {
+
+	fn new( session: u64, connect: Option<Box<dyn FnMut() -> Channel>> ) -> Self {
+		Self {
+			session,
+			channel: None,
+			connect,
+			backoff: Backoff {
+				initial: Duration::from_secs( 1 ),
+				max: Duration::from_secs( 60 ),
+				attempt: 0
+			},
+			reconnect_delay: None,
+			state: ConnectionState::Disconnected,
+			events: VecDeque::new(),
+			next_sequence: 0,
+			unacknowledged: VecDeque::new(),
+			received: 0,
+			incoming: VecDeque::new(),
+			receive_waker: None,
+			send_waker: None,
+			event_waker: None
+		}
+	}
+
+	/// Tells the other side where we are, and sends everything again that it hasn't acknowledged.
+	fn attach( &mut self, channel: Channel ) {
+		self.channel = Some( channel );
+		self.send_frame( resume_envelope( self.session, self.received ) );
+
+		let replay: Vec<_> = self.unacknowledged.iter().map( |( sequence, message )| data_envelope( *sequence, message ) ).collect();
+		for envelope in replay {
+			self.send_frame( envelope );
+		}
+
+		if self.channel.is_some() {
+			self.set_state( ConnectionState::Connected );
+		}
+		self.wake_all();
+	}
+
+	fn acknowledge( &mut self, sequence: u64 ) {
+		while let Some( ( s, _ ) ) = self.unacknowledged.front() {
+			if *s > sequence { break }
+			self.unacknowledged.pop_front();
+		}
+	}
+
+	fn disconnected( &mut self ) {
+		if self.channel.take().is_none() || self.state == ConnectionState::Closed {
+			return
+		}
+
+		if self.connect.is_some() {
+			let delay = self.backoff.next();
+			self.reconnect_delay = Some( scheduler::delay( delay ) );
+			self.set_state( ConnectionState::Reconnecting { attempt: self.backoff.attempt } );
+		}
+		else {
+			self.set_state( ConnectionState::Disconnected );
+		}
+		self.wake_all();
+	}
+
+	fn handle_frame( &mut self, message: mq::Message ) {
+		let payload = &message.payload;
+
+		match message.type_ {
+			DATA_MESSAGE_TYPE if payload.len() >= 10 => {
+				let sequence = u64::from_be_bytes( payload[..8].try_into().unwrap() );
+
+				// Messages that have been received before are replays.
+				if sequence == self.received + 1 {
+					self.received = sequence;
+					self.incoming.push_back( mq::Message {
+						type_: u16::from_be_bytes( payload[8..10].try_into().unwrap() ),
+						payload: payload[10..].to_vec()
+					} );
+				}
+				self.send_frame( ack_envelope( self.received ) );
+			},
+			ACK_MESSAGE_TYPE if payload.len() >= 8 => {
+				self.acknowledge( u64::from_be_bytes( payload[..8].try_into().unwrap() ) );
+			},
+			RESUME_MESSAGE_TYPE => if let Some( ( _, received ) ) = parse_resume( &message ) {
+				self.acknowledge( received );
+			},
+			_ => return
+		}
+
+		// Messages get through, so the next disconnect starts over.
+		self.backoff.attempt = 0;
+		self.wake_all();
+	}
+
+	/// Handles everything that happened on the channel, and reconnects when it is time to.
+	fn poll_channel( &mut self, cx: &mut Context<'_> ) {
+		loop {
+			if self.channel.is_none() {
+				match &mut self.reconnect_delay {
+					None => return,
+					Some( delay ) => if Pin::new( delay ).poll( cx ).is_pending() {
+						return
+					}
+				}
+				self.reconnect_delay = None;
+
+				let channel = (self.connect.as_mut().unwrap())();
+				self.attach( channel );
+				continue
+			}
+
+			match Pin::new( self.channel.as_mut().unwrap() ).poll_next( cx ) {
+				Poll::Pending => return,
+				Poll::Ready( Some( message ) ) => self.handle_frame( message ),
+				Poll::Ready( None ) => self.disconnected()
+			}
+		}
+	}
+
+	/// Continues a session on a channel that the other side has created.
+	fn resume( &mut self, channel: Channel, received: u64 ) {
+		self.acknowledge( received );
+		self.attach( channel );
+	}
+
+	fn send_frame( &mut self, envelope: mq::Envelope ) {
+		let result = match &mut self.channel {
+			Some( channel ) => Pin::new( channel ).start_send( envelope ),
+			None => return
+		};
+
+		if result.is_err() {
+			self.disconnected();
+		}
+	}
+
+	fn set_state( &mut self, state: ConnectionState ) {
+		if self.state != state {
+			self.state = state;
+			self.events.push_back( state );
+		}
+	}
+
+	fn wake_all( &mut self ) {
+		for waker in [ self.receive_waker.take(), self.send_waker.take(), self.event_waker.take() ].iter_mut() {
+			if let Some( waker ) = waker.take() {
+				waker.wake();
+			}
+		}
+	}
+}
+
+
+
+fn ack_envelope( sequence: u64 ) -> mq::Envelope {
+	mq::Envelope::new( ACK_MESSAGE_TYPE, &sequence.to_be_bytes() )
+}
+
+fn data_envelope( sequence: u64, message: &mq::Message ) -> mq::Envelope {
+	let mut payload = Vec::with_capacity( 10 + message.payload.len() );
+	payload.extend_from_slice( &sequence.to_be_bytes() );
+	payload.extend_from_slice( &message.type_.to_be_bytes() );
+	payload.extend_from_slice( &message.payload );
+
+	mq::Envelope::new( DATA_MESSAGE_TYPE, &payload )
+}
+
+/// Returns the session and the last received sequence number of a resume message.
+fn parse_resume( message: &mq::Message ) -> Option<( u64, u64 )> {
+	if message.type_ != RESUME_MESSAGE_TYPE || message.payload.len() < 16 {
+		return None
+	}
+
+	Some( (
+		u64::from_be_bytes( message.payload[..8].try_into().unwrap() ),
+		u64::from_be_bytes( message.payload[8..16].try_into().unwrap() )
+	) )
+}
+
+fn resume_envelope( session: u64, received: u64 ) -> mq::Envelope {
+	let mut payload = Vec::with_capacity( 16 );
+	payload.extend_from_slice( &session.to_be_bytes() );
+	payload.extend_from_slice( &received.to_be_bytes() );
+
+	mq::Envelope::new( RESUME_MESSAGE_TYPE, &payload )
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{panic::{self, AssertUnwindSafe}, sync::mpsc};
+
+	use futures::{future, SinkExt, StreamExt};
+
+	use crate::cadet::{tests::{take_sent, test_channel}, ChannelState};
+	use crate::configuration::Configuration;
+	use crate::runtime::Runtime;
+
+	fn data_frame( sequence: u64, message: &mq::Message ) -> mq::Message {
+		data_envelope( sequence, message ).to_message()
+	}
+
+	fn ack_frame( sequence: u64 ) -> mq::Message {
+		ack_envelope( sequence ).to_message()
+	}
+
+	fn message( type_: u16, payload: &[u8] ) -> mq::Message {
+		mq::Message { type_, payload: payload.to_vec() }
+	}
+
+	/// Lets the channel handle what has been put in the queue of its current channel.
+	async fn pump( channel: &mut ResilientChannel ) {
+		future::poll_fn( |cx| {
+			let _ = Pin::new( &mut *channel ).poll_next( cx );
+			Poll::Ready( () )
+		} ).await
+	}
+
+	/// Runs the test on the Gnunet scheduler, because reconnecting waits for a delay of it.
+	fn run_local<F, T>( create: F ) where
+		F: FnOnce() -> T + Send + 'static,
+		T: Future<Output=()> + 'static
+	{
+		let runtime = Runtime::new( Configuration::new() ).expect("unable to start runtime");
+		let ( done, finished ) = mpsc::channel();
+
+		runtime.spawn_local( move || {
+			let mut create = Some( create );
+			let mut test = None;
+
+			// A panic can't unwind through the Gnunet scheduler, so it is caught here and resumed on the thread of the test.
+			future::poll_fn( move |cx| {
+				let result = panic::catch_unwind( AssertUnwindSafe( || {
+					let test = test.get_or_insert_with( || Box::pin( create.take().unwrap()() ) );
+					test.as_mut().poll( cx )
+				} ) );
+
+				match result {
+					Ok( Poll::Pending ) => Poll::Pending,
+					Ok( Poll::Ready( () ) ) => {
+						let _ = done.send( Ok(()) );
+						Poll::Ready( () )
+					},
+					Err( panic ) => {
+						let _ = done.send( Err( panic ) );
+						Poll::Ready( () )
+					}
+				}
+			} )
+		} );
+
+		match finished.recv_timeout( Duration::from_secs( 5 ) ) {
+			Ok( Ok(()) ) => {},
+			Ok( Err( panic ) ) => panic::resume_unwind( panic ),
+			Err( _ ) => panic!("test didn't finish on the runtime")
+		}
+	}
+
+	#[test]
+	fn unacknowledged_messages_are_replayed_after_reconnecting() {
+		run_local( || async {
+			let states: Rc<RefCell<Vec<Rc<RefCell<ChannelState>>>>> = Rc::default();
+			let states2 = states.clone();
+
+			let mut channel = ResilientChannel::connect( move || {
+				let ( channel, state ) = test_channel();
+				states2.borrow_mut().push( state );
+				channel
+			} );
+			channel.set_backoff( Duration::from_millis( 1 ), Duration::from_millis( 1 ) );
+			let mut events = channel.events();
+			assert_eq!( events.next().await, Some( ConnectionState::Connected ) );
+
+			let sent = take_sent();
+			assert_eq!( sent.len(), 1 );
+			let ( session, received ) = parse_resume( &sent[0] ).unwrap();
+			assert_eq!( received, 0 );
+
+			channel.feed( message( 1, b"first" ) ).await.unwrap();
+			channel.feed( message( 2, b"second" ) ).await.unwrap();
+			assert_eq!( take_sent(), vec![ data_frame( 1, &message( 1, b"first" ) ), data_frame( 2, &message( 2, b"second" ) ) ] );
+
+			// Only the first message gets acknowledged before the channel goes down.
+			states.borrow()[0].borrow_mut().incoming.push_back( ack_frame( 1 ) );
+			pump( &mut channel ).await;
+			assert_eq!( channel.unacknowledged(), 1 );
+
+			states.borrow()[0].borrow_mut().disconnected = true;
+			assert_eq!( events.next().await, Some( ConnectionState::Reconnecting { attempt: 1 } ) );
+			assert_eq!( events.next().await, Some( ConnectionState::Connected ) );
+			assert_eq!( states.borrow().len(), 2 );
+
+			// The new channel resumes the session, and sends the second message again.
+			let sent = take_sent();
+			assert_eq!( sent.len(), 2 );
+			assert_eq!( parse_resume( &sent[0] ), Some( ( session, 0 ) ) );
+			assert_eq!( sent[1], data_frame( 2, &message( 2, b"second" ) ) );
+
+			states.borrow()[1].borrow_mut().incoming.push_back( ack_frame( 2 ) );
+			pump( &mut channel ).await;
+			assert_eq!( channel.unacknowledged(), 0 );
+
+			states.borrow()[1].borrow_mut().disconnected = true;
+		} );
+	}
+
+	#[test]
+	fn replayed_messages_are_received_once() {
+		run_local( || async {
+			let states: Rc<RefCell<Vec<Rc<RefCell<ChannelState>>>>> = Rc::default();
+			let states2 = states.clone();
+
+			let mut channel = ResilientChannel::connect( move || {
+				let ( channel, state ) = test_channel();
+				states2.borrow_mut().push( state );
+				channel
+			} );
+			take_sent();
+
+			// The other side sends the first message twice, as it does after a reconnect.
+			{
+				let states = states.borrow();
+				let mut state = states[0].borrow_mut();
+				state.incoming.push_back( data_frame( 1, &message( 7, b"once" ) ) );
+				state.incoming.push_back( data_frame( 1, &message( 7, b"once" ) ) );
+				state.incoming.push_back( data_frame( 2, &message( 8, b"twice" ) ) );
+			}
+
+			assert_eq!( channel.next().await, Some( message( 7, b"once" ) ) );
+			assert_eq!( channel.next().await, Some( message( 8, b"twice" ) ) );
+			assert_eq!( take_sent(), vec![ ack_frame( 1 ), ack_frame( 1 ), ack_frame( 2 ) ] );
+
+			states.borrow()[0].borrow_mut().disconnected = true;
+		} );
+	}
+}
@@ -0,0 +1,349 @@
+//! Requests and responses over CADET channels.
+//!
+//! A `Server` listens on a port and answers the requests of every channel that connects to it, with the handler that is registered for the requested method.
+//! A `Client` sends requests over a single channel, of which any number can be waiting for their response at the same time.
+//!
+//! Requests are messages of type `REQUEST_MESSAGE_TYPE`, of which the payload starts with a 32-bit request id and a 16-bit method id, followed by the encoded request.
+//! Responses are messages of type `RESPONSE_MESSAGE_TYPE`, of which the payload starts with the id of the request and a status byte, followed by the encoded response.
+//! All numbers are in network byte order.
+
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	convert::TryInto,
+	error, fmt,
+	future::Future,
+	marker::PhantomData,
+	pin::Pin,
+	rc::Rc,
+	task::{Context, Poll, Waker},
+	time::Duration
+};
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+use crate::crypto::PeerIdentity;
+use crate::mq::{self, Decode, Encode};
+use crate::scheduler;
+use super::{Channel, Handle, PortId};
+
+
+
+pub const REQUEST_MESSAGE_TYPE: u16 = 0xFF01;
+pub const RESPONSE_MESSAGE_TYPE: u16 = 0xFF02;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_METHOD: u8 = 1;
+const STATUS_INVALID_REQUEST: u8 = 2;
+
+/// A future that resolves to the response of a request.
+/// Dropping it cancels the request, after which its response is ignored.
+pub struct Call<R> {
+	id: u32,
+	envelope: Option<mq::Envelope>,
+	timeout: Option<scheduler::Delay>,
+	state: Rc<RefCell<ClientState>>,
+	_response: PhantomData<fn() -> R>
+}
+
+/// Sends requests over a channel.
+pub struct Client {
+	state: Rc<RefCell<ClientState>>,
+	timeout: Option<Duration>
+}
+
+struct ClientState {
+	channel: Channel,
+	next_id: u32,
+	pending: HashMap<u32, PendingCall>
+}
+
+#[derive(Debug)]
+pub enum Error {
+	/// The channel has been disconnected before the response was received.
+	Disconnected,
+	/// The server doesn't know the method.
+	UnknownMethod,
+	/// The server was not able to decode the request.
+	InvalidRequest,
+	/// The response could not be decoded.
+	InvalidResponse,
+	/// No response has been received in time.
+	Timeout
+}
+
+type Method = Box<dyn FnMut( &[u8] ) -> Option<Vec<u8>>>;
+
+#[derive(Default)]
+struct PendingCall {
+	response: Option<Result<Vec<u8>, Error>>,
+	waker: Option<Waker>
+}
+
+/// Answers requests with the handlers of its methods.
+pub struct Server {
+	methods: HashMap<u16, Method>
+}
+
+
+
+impl<R> Future for Call<R> where
+	R: Decode
+{
+	type Output = Result<R, Error>;
+
+	fn poll( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Result<R, Error>> {
+		let this = self.get_mut();
+		let mut state = this.state.borrow_mut();
+
+		// Send the request first, when the channel allows it.
+		if let Some( envelope ) = this.envelope.take() {
+			match Pin::new( &mut state.channel ).poll_ready( cx ) {
+				Poll::Pending => {
+					// The timeout includes the time that the request waits for the channel.
+					if timed_out( &mut this.timeout, cx ) {
+						return Poll::Ready( state.finish( this.id, Err( Error::Timeout ) ) )
+					}
+
+					this.envelope = Some( envelope );
+					state.register( this.id, cx.waker() );
+					return Poll::Pending
+				},
+				Poll::Ready( Err(_) ) => return Poll::Ready( state.finish( this.id, Err( Error::Disconnected ) ) ),
+				Poll::Ready( Ok(()) ) => if Pin::new( &mut state.channel ).start_send( envelope ).is_err() {
+					return Poll::Ready( state.finish( this.id, Err( Error::Disconnected ) ) )
+				}
+			}
+		}
+
+		// Any call that polls receives the responses for all of them.
+		loop {
+			if let Some( response ) = state.pending.get_mut( &this.id ).and_then( |p| p.response.take() ) {
+				let result = response.and_then( |payload| R::decode( &payload ).ok_or( Error::InvalidResponse ) );
+				return Poll::Ready( state.finish( this.id, result ) )
+			}
+
+			match Pin::new( &mut state.channel ).poll_next( cx ) {
+				Poll::Ready( Some( message ) ) => state.dispatch( message ),
+				Poll::Ready( None ) => return Poll::Ready( state.finish( this.id, Err( Error::Disconnected ) ) ),
+				Poll::Pending => break
+			}
+		}
+
+		if timed_out( &mut this.timeout, cx ) {
+			return Poll::Ready( state.finish( this.id, Err( Error::Timeout ) ) )
+		}
+
+		state.register( this.id, cx.waker() );
+		Poll::Pending
+	}
+}
+
+impl<R> Drop for Call<R> {
+
+	fn drop( &mut self ) {
+		let mut state = self.state.borrow_mut();
+		if state.pending.contains_key( &self.id ) {
+			state.finish( self.id, () );
+		}
+	}
+}
+
+impl Client {
+
+	/// Creates a channel to the server at the given peer and port.
+	pub fn connect( handle: &mut Handle, destination: &PeerIdentity, port: &PortId ) -> Self {
+		Self::new( handle.open_channel( destination, port, &[RESPONSE_MESSAGE_TYPE] ) )
+	}
+
+	/// Uses a channel that has been created with `Handle::open_channel`, which needs to receive messages of type `RESPONSE_MESSAGE_TYPE` through its stream.
+	pub fn new( channel: Channel ) -> Self {
+		Self {
+			state: Rc::new( RefCell::new( ClientState {
+				channel,
+				next_id: 0,
+				pending: HashMap::new()
+			} ) ),
+			timeout: None
+		}
+	}
+
+	/// Sends a request to the given method, and returns a future of its response.
+	/// The request is sent when the future is polled for the first time.
+	pub fn call<Q, R>( &self, method: u16, request: &Q ) -> Call<R> where
+		Q: Encode + ?Sized,
+		R: Decode
+	{
+		let id = {
+			let mut state = self.state.borrow_mut();
+			state.next_id = state.next_id.wrapping_add( 1 );
+			let id = state.next_id;
+			state.pending.insert( id, PendingCall::default() );
+			id
+		};
+
+		let mut payload = Vec::new();
+		payload.extend_from_slice( &id.to_be_bytes() );
+		payload.extend_from_slice( &method.to_be_bytes() );
+		payload.extend_from_slice( &request.encode() );
+
+		Call {
+			id,
+			envelope: Some( mq::Envelope::new( REQUEST_MESSAGE_TYPE, &payload ) ),
+			timeout: self.timeout.map( scheduler::delay ),
+			state: self.state.clone(),
+			_response: PhantomData
+		}
+	}
+
+	/// The number of requests that are waiting for their response.
+	pub fn pending( &self ) -> usize {
+		self.state.borrow().pending.len()
+	}
+
+	/// Sets the time after which calls fail with `Error::Timeout`, for the calls that are made after this.
+	pub fn set_timeout( &mut self, timeout: Option<Duration> ) {
+		self.timeout = timeout;
+	}
+}
+
+impl ClientState {
+
+	/// Stores a response for the call that it belongs to.
+	/// Responses of calls that have been cancelled are ignored.
+	fn dispatch( &mut self, message: mq::Message ) {
+		if message.payload.len() < 5 {
+			return
+		}
+
+		let id = u32::from_be_bytes( message.payload[..4].try_into().unwrap() );
+		let response = match message.payload[4] {
+			STATUS_OK => Ok( message.payload[5..].to_vec() ),
+			STATUS_UNKNOWN_METHOD => Err( Error::UnknownMethod ),
+			_ => Err( Error::InvalidRequest )
+		};
+
+		if let Some( pending ) = self.pending.get_mut( &id ) {
+			pending.response = Some( response );
+			if let Some( waker ) = pending.waker.take() {
+				waker.wake();
+			}
+		}
+	}
+
+	/// Removes the call and wakes the other ones.
+	/// The channel only remembers the waker of the last call that polled it, so another call needs to take over receiving.
+	fn finish<T>( &mut self, id: u32, result: T ) -> T {
+		self.pending.remove( &id );
+
+		for pending in self.pending.values_mut() {
+			if let Some( waker ) = pending.waker.take() {
+				waker.wake();
+			}
+		}
+		result
+	}
+
+	fn register( &mut self, id: u32, waker: &Waker ) {
+		if let Some( pending ) = self.pending.get_mut( &id ) {
+			pending.waker = Some( waker.clone() );
+		}
+	}
+}
+
+impl fmt::Display for Error {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Disconnected => write!(f, "channel disconnected"),
+			Error::UnknownMethod => write!(f, "unknown method"),
+			Error::InvalidRequest => write!(f, "invalid request"),
+			Error::InvalidResponse => write!(f, "invalid response"),
+			Error::Timeout => write!(f, "request timed out")
+		}
+	}
+}
+
+impl error::Error for Error {}
+
+impl Server {
+
+	pub fn new() -> Self {
+		Self {
+			methods: HashMap::new()
+		}
+	}
+
+	/// Adds the handler of a method.
+	///
+	/// # Panics
+	/// When a handler for the method has been added already.
+	pub fn method<Q, R, F>( mut self, id: u16, mut handler: F ) -> Self where
+		Q: Decode,
+		R: Encode,
+		F: FnMut( Q ) -> R + 'static
+	{
+		let method: Method = Box::new( move |payload| {
+			Q::decode( payload ).map( |request| handler( request ).encode() )
+		} );
+
+		assert!( self.methods.insert( id, method ).is_none(), "method {} added twice", id );
+		self
+	}
+
+	/// Opens the port, and returns a future that answers the requests of all channels that connect to it.
+	/// The port is closed when the future is dropped.
+	pub fn serve( self, handle: &mut Handle, port: &PortId ) -> impl Future<Output=()> {
+		let listener = handle.listen( port, &[REQUEST_MESSAGE_TYPE] );
+		let methods = Rc::new( RefCell::new( self.methods ) );
+
+		listener.for_each_concurrent( None, move |( channel, _ )| serve_channel( channel, methods.clone() ) )
+	}
+}
+
+impl Default for Server {
+
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+
+
+/// Answers the requests of a channel one by one, until it disconnects.
+async fn serve_channel( mut channel: Channel, methods: Rc<RefCell<HashMap<u16, Method>>> ) {
+
+	while let Some( message ) = channel.next().await {
+		if message.payload.len() < 6 {
+			continue
+		}
+
+		let id = &message.payload[..4];
+		let method = u16::from_be_bytes( message.payload[4..6].try_into().unwrap() );
+
+		let ( status, response ) = match methods.borrow_mut().get_mut( &method ) {
+			None => ( STATUS_UNKNOWN_METHOD, Vec::new() ),
+			Some( handler ) => match handler( &message.payload[6..] ) {
+				Some( response ) => ( STATUS_OK, response ),
+				None => ( STATUS_INVALID_REQUEST, Vec::new() )
+			}
+		};
+
+		let mut payload = Vec::with_capacity( 5 + response.len() );
+		payload.extend_from_slice( id );
+		payload.push( status );
+		payload.extend_from_slice( &response );
+
+		if channel.send( mq::Envelope::new( RESPONSE_MESSAGE_TYPE, &payload ) ).await.is_err() {
+			break
+		}
+	}
+}
+
+/// Polls the timeout of a call, if it has one.
+fn timed_out( timeout: &mut Option<scheduler::Delay>, cx: &mut Context<'_> ) -> bool {
+	match timeout {
+		Some( timeout ) => Pin::new( timeout ).poll( cx ).is_ready(),
+		None => false
+	}
+}
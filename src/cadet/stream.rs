@@ -0,0 +1,159 @@
+use gnunet_sys::*;
+
+use std::{
+	cmp, io, mem,
+	pin::Pin,
+	task::{Context, Poll}
+};
+
+use futures::{
+	io::{AsyncRead, AsyncWrite},
+	ready, Sink
+};
+
+use crate::crypto::PeerIdentity;
+use crate::mq;
+use super::{Channel, ChannelError, Handle, PortId, MAX_MESSAGE_SIZE};
+
+
+
+/// The message type of the messages that carry the bytes of a `Stream`.
+/// It lies outside of the range used by Gnunet's own services.
+pub const STREAM_MESSAGE_TYPE: u16 = 0xFF00;
+
+/// The maximum number of bytes that are put in a single message, which is what fits in a CADET message after its header.
+const MAX_CHUNK_SIZE: usize = MAX_MESSAGE_SIZE - mem::size_of::<GNUNET_MessageHeader>();
+
+/// A reliable byte stream over a channel, like a TCP connection.
+///
+/// Every write is sent as one or more messages of type `STREAM_MESSAGE_TYPE`, and the other side reads their payloads back in order.
+/// Writes wait while the window of the channel is full.
+pub struct Stream {
+	channel: Channel,
+	buffer: Vec<u8>,
+	position: usize
+}
+
+
+
+impl Stream {
+
+	/// Creates a channel to the given peer and port, and wraps it.
+	pub fn connect( handle: &mut Handle, destination: &PeerIdentity, port: &PortId ) -> Self {
+		Self::new( handle.open_channel( destination, port, &[STREAM_MESSAGE_TYPE] ) )
+	}
+
+	/// Wraps a channel that has been created with `Handle::open_channel` or received from a `Listener`.
+	/// The channel needs to receive messages of type `STREAM_MESSAGE_TYPE` through its stream.
+	pub fn new( channel: Channel ) -> Self {
+		Self {
+			channel,
+			buffer: Vec::new(),
+			position: 0
+		}
+	}
+
+	/// The channel that the stream is sent over.
+	pub fn channel( &self ) -> &Channel {
+		&self.channel
+	}
+
+	/// Unwraps the channel.
+	/// Bytes that have been received but not read yet are lost.
+	pub fn into_channel( self ) -> Channel {
+		self.channel
+	}
+
+	fn poll_read_inner( &mut self, cx: &mut Context<'_>, buf: &mut [u8] ) -> Poll<io::Result<usize>> {
+
+		while self.position == self.buffer.len() {
+			match ready!( futures::Stream::poll_next( Pin::new( &mut self.channel ), cx ) ) {
+				// The channel only receives messages of our own type, but the other side may send empty ones.
+				Some( message ) => {
+					self.buffer = message.payload;
+					self.position = 0;
+				},
+				// End of stream.
+				None => return Poll::Ready( Ok( 0 ) )
+			}
+		}
+
+		let n = cmp::min( buf.len(), self.buffer.len() - self.position );
+		buf[..n].copy_from_slice( &self.buffer[ self.position .. self.position + n ] );
+		self.position += n;
+		Poll::Ready( Ok( n ) )
+	}
+
+	fn poll_write_inner( &mut self, cx: &mut Context<'_>, buf: &[u8] ) -> Poll<io::Result<usize>> {
+		let mut channel = Pin::new( &mut self.channel );
+
+		ready!( channel.as_mut().poll_ready( cx ) ).map_err( to_io_error )?;
+
+		let n = cmp::min( buf.len(), MAX_CHUNK_SIZE );
+		channel.start_send( mq::Envelope::new( STREAM_MESSAGE_TYPE, &buf[..n] ) ).map_err( to_io_error )?;
+		Poll::Ready( Ok( n ) )
+	}
+
+	fn poll_flush_inner( &mut self, cx: &mut Context<'_> ) -> Poll<io::Result<()>> {
+		Pin::new( &mut self.channel ).poll_flush( cx ).map_err( to_io_error )
+	}
+
+	fn poll_close_inner( &mut self, cx: &mut Context<'_> ) -> Poll<io::Result<()>> {
+		Pin::new( &mut self.channel ).poll_close( cx ).map_err( to_io_error )
+	}
+}
+
+impl AsyncRead for Stream {
+
+	fn poll_read( self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8] ) -> Poll<io::Result<usize>> {
+		self.get_mut().poll_read_inner( cx, buf )
+	}
+}
+
+impl AsyncWrite for Stream {
+
+	fn poll_write( self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8] ) -> Poll<io::Result<usize>> {
+		self.get_mut().poll_write_inner( cx, buf )
+	}
+
+	fn poll_flush( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<io::Result<()>> {
+		self.get_mut().poll_flush_inner( cx )
+	}
+
+	/// Waits until everything has been sent, and destroys the channel.
+	fn poll_close( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<io::Result<()>> {
+		self.get_mut().poll_close_inner( cx )
+	}
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for Stream {
+
+	fn poll_read( self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_> ) -> Poll<io::Result<()>> {
+		let n = ready!( self.get_mut().poll_read_inner( cx, buf.initialize_unfilled() ) )?;
+		buf.advance( n );
+		Poll::Ready( Ok(()) )
+	}
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for Stream {
+
+	fn poll_write( self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8] ) -> Poll<io::Result<usize>> {
+		self.get_mut().poll_write_inner( cx, buf )
+	}
+
+	fn poll_flush( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<io::Result<()>> {
+		self.get_mut().poll_flush_inner( cx )
+	}
+
+	fn poll_shutdown( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<io::Result<()>> {
+		self.get_mut().poll_close_inner( cx )
+	}
+}
+
+
+
+fn to_io_error( error: ChannelError ) -> io::Error {
+	io::Error::new( io::ErrorKind::BrokenPipe, error )
+}
@@ -0,0 +1,340 @@
+//! Ownership of Rust closures that are handed to the C API as a `cls` pointer.
+//!
+//! There are two kinds of callbacks:
+//! * One-shot callbacks ([`Once`]), which the C API calls once to complete an operation.
+//!   Both the operation handle and the C API own such a closure, because the operation can be cancelled before the callback has been called.
+//!   Callbacks that are called repeatedly until a final call (like iterations) are handled the same way, the final call releases the closure.
+//! * Repeating callbacks ([`Repeating`]), which the C API calls any number of times until their owner unregisters them.
+//!   Such closures are only owned by the handle (like a channel, port or connection), which frees it after unregistering.
+
+use std::{
+	cell::RefCell,
+	mem::{self, ManuallyDrop},
+	os::raw::*,
+	rc::Rc
+};
+
+
+
+/// A closure that is released by the C API calling it for the last time, or by its owner cancelling it.
+pub(in crate) struct Once {
+	slot: Rc<dyn Slot>,
+	cls: *mut c_void,
+	release: unsafe fn( *mut c_void )
+}
+
+/// A closure that the C API may call any number of times, until its owner is dropped.
+pub(in crate) struct Repeating {
+	cls: *mut c_void,
+	free: unsafe fn( *mut c_void )
+}
+
+trait Slot {
+	fn clear( &self ) -> bool;
+	fn is_pending( &self ) -> bool;
+}
+
+/// The state of the closure of a `Once`.
+enum State<F> {
+	Pending( F ),
+	/// The closure is taken out of its slot while it is being called by `Once::borrow`.
+	Calling,
+	/// The closure has been called for the last time, or has been cancelled.
+	Released
+}
+
+
+
+impl Once {
+
+	pub fn new<F>( closure: F ) -> Self where
+		F: 'static
+	{
+		let slot = Rc::new( RefCell::new( State::Pending( closure ) ) );
+		let cls = Rc::into_raw( slot.clone() ) as *mut c_void;
+
+		Self {
+			slot,
+			cls,
+			release: release::<F>
+		}
+	}
+
+	/// Calls `f` with the closure, without releasing it.
+	/// Returns `None` if the closure has been released already.
+	///
+	/// # Safety
+	/// `cls` must be the pointer of a `Once` created for `F`, which the C API has not released yet.
+	pub unsafe fn borrow<F, R, G>( cls: *mut c_void, f: G ) -> Option<R> where
+		G: FnOnce( &mut F ) -> R
+	{
+		// Hold a reference of our own, because the closure may cancel itself and drop its owner.
+		let slot = ManuallyDrop::new( Rc::from_raw( cls as *const RefCell<State<F>> ) );
+		let slot: Rc<RefCell<State<F>>> = Rc::clone( &slot );
+
+		// Take the closure out while calling it, so that a cancellation from within the closure doesn't panic.
+		let state = mem::replace( &mut *slot.borrow_mut(), State::Calling );
+		let mut closure = match state {
+			State::Pending( closure ) => closure,
+			other => {
+				*slot.borrow_mut() = other;
+				return None
+			}
+		};
+		let result = f( &mut closure );
+
+		// If the closure has been cancelled in the meantime, it is dropped instead of put back.
+		let mut state = slot.borrow_mut();
+		if let State::Calling = *state {
+			*state = State::Pending( closure );
+		}
+		else {
+			drop( state );
+			drop( closure );
+		}
+		Some( result )
+	}
+
+	/// Cancels the closure if it has not been called for the last time yet.
+	/// Returns whether it was still pending.
+	///
+	/// # Safety
+	/// The C operation must have been cancelled first, so that it won't call the closure anymore.
+	pub unsafe fn cancel( &self ) -> bool {
+		if self.slot.clear() {
+			(self.release)( self.cls );
+			true
+		}
+		else {
+			false
+		}
+	}
+
+	/// The pointer to give to the C API.
+	pub fn cls( &self ) -> *mut c_void {
+		self.cls
+	}
+
+	/// Whether the closure is still waiting for its (last) call.
+	pub fn is_pending( &self ) -> bool {
+		self.slot.is_pending()
+	}
+
+	/// Takes the closure out for its last call, releasing the reference that the C API holds.
+	/// Returns `None` if the closure has been cancelled.
+	///
+	/// # Safety
+	/// `cls` must be the pointer of a `Once` created for `F`, and may not be used by the C API afterwards.
+	pub unsafe fn take<F>( cls: *mut c_void ) -> Option<F> {
+		let slot = Rc::from_raw( cls as *const RefCell<State<F>> );
+		let state = mem::replace( &mut *slot.borrow_mut(), State::Released );
+
+		match state {
+			State::Pending( closure ) => Some( closure ),
+			_ => None
+		}
+	}
+}
+
+impl Repeating {
+
+	pub fn new<F>( closure: F ) -> Self {
+		Self {
+			cls: Box::into_raw( Box::new( closure ) ) as _,
+			free: free::<F>
+		}
+	}
+
+	/// The pointer to give to the C API.
+	pub fn cls( &self ) -> *mut c_void {
+		self.cls
+	}
+
+	/// Borrows the closure from within a callback.
+	///
+	/// # Safety
+	/// `cls` must be the pointer of a `Repeating` created for `F`, which is still alive.
+	pub unsafe fn get<'a, F>( cls: *mut c_void ) -> &'a mut F {
+		&mut *(cls as *mut F)
+	}
+}
+
+impl Drop for Repeating {
+
+	/// The owner is responsible for unregistering the closure from the C API before this.
+	fn drop( &mut self ) {
+		unsafe { (self.free)( self.cls ) };
+	}
+}
+
+impl<F> Slot for RefCell<State<F>> {
+
+	/// A closure that is being called is cancelled as well, and is dropped when the call returns.
+	fn clear( &self ) -> bool {
+		let state = mem::replace( &mut *self.borrow_mut(), State::Released );
+		!matches!( state, State::Released )
+	}
+
+	fn is_pending( &self ) -> bool {
+		!matches!( *self.borrow(), State::Released )
+	}
+}
+
+
+
+unsafe fn free<F>( cls: *mut c_void ) {
+	drop( Box::from_raw( cls as *mut F ) );
+}
+
+unsafe fn release<F>( cls: *mut c_void ) {
+	drop( Rc::from_raw( cls as *const RefCell<State<F>> ) );
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::cell::Cell;
+
+	/// Counts how often it is dropped, so that the tests can check that closures are freed exactly once.
+	struct DropCounter ( Rc<Cell<usize>> );
+
+	impl Drop for DropCounter {
+		fn drop( &mut self ) {
+			self.0.set( self.0.get() + 1 );
+		}
+	}
+
+	/// Returns the function that the C API would call with the `cls` of a closure of type `F`.
+	fn caller<F: FnMut()>( _: &F ) -> unsafe fn( *mut c_void ) {
+		call_repeating::<F>
+	}
+
+	unsafe fn call_repeating<F: FnMut()>( cls: *mut c_void ) {
+		Repeating::get::<F>( cls )();
+	}
+
+	/// Returns the function that the C API would call with the `cls` of a `Once` of type `F`, for calls that are not the last.
+	fn borrower<F: FnMut()>( _: &F ) -> unsafe fn( *mut c_void ) -> Option<()> {
+		call_once::<F>
+	}
+
+	unsafe fn call_once<F: FnMut()>( cls: *mut c_void ) -> Option<()> {
+		Once::borrow( cls, |f: &mut F| f() )
+	}
+
+	fn counting_closure( calls: &Rc<Cell<usize>>, drops: &Rc<Cell<usize>> ) -> impl FnMut() + 'static {
+		let calls = calls.clone();
+		let guard = DropCounter ( drops.clone() );
+
+		move || {
+			let _ = &guard;
+			calls.set( calls.get() + 1 );
+		}
+	}
+
+	#[test]
+	fn repeating_is_called_repeatedly_and_freed_on_drop() {
+		let calls = Rc::new( Cell::new( 0 ) );
+		let drops = Rc::new( Cell::new( 0 ) );
+
+		let closure = counting_closure( &calls, &drops );
+		let call = caller( &closure );
+		let repeating = Repeating::new( closure );
+
+		for _ in 0..3 {
+			unsafe { call( repeating.cls() ) };
+		}
+		assert_eq!( calls.get(), 3 );
+		assert_eq!( drops.get(), 0 );
+
+		drop( repeating );
+		assert_eq!( drops.get(), 1 );
+	}
+
+	#[test]
+	fn once_is_borrowed_repeatedly_then_taken() {
+		let calls = Rc::new( Cell::new( 0 ) );
+		let drops = Rc::new( Cell::new( 0 ) );
+
+		let closure = counting_closure( &calls, &drops );
+		let once = Once::new( closure );
+		let cls = once.cls();
+
+		fn take<F: FnMut()>( _: &F, cls: *mut c_void ) -> Option<F> {
+			unsafe { Once::take::<F>( cls ) }
+		}
+
+		// A closure of the same type, to name the type of the closure that has been moved into the `Once`.
+		let witness = counting_closure( &calls, &drops );
+		let call = borrower( &witness );
+		for _ in 0..2 {
+			assert!( unsafe { call( cls ) }.is_some() );
+		}
+		assert_eq!( calls.get(), 2 );
+		assert!( once.is_pending() );
+
+		// The last call releases the reference of the C API.
+		let mut last = take( &witness, cls ).expect("closure released too early");
+		last();
+		assert_eq!( calls.get(), 3 );
+		assert!( !once.is_pending() );
+
+		drop( last );
+		drop( witness );
+		assert_eq!( drops.get(), 2 );
+
+		// Cancelling after the last call does nothing.
+		assert!( !unsafe { once.cancel() } );
+		drop( once );
+		assert_eq!( drops.get(), 2 );
+	}
+
+	#[test]
+	fn once_cancelled_before_call() {
+		let calls = Rc::new( Cell::new( 0 ) );
+		let drops = Rc::new( Cell::new( 0 ) );
+
+		let once = Once::new( counting_closure( &calls, &drops ) );
+		assert!( once.is_pending() );
+
+		assert!( unsafe { once.cancel() } );
+		assert!( !once.is_pending() );
+		assert_eq!( drops.get(), 1 );
+
+		assert!( !unsafe { once.cancel() } );
+		drop( once );
+		assert_eq!( calls.get(), 0 );
+		assert_eq!( drops.get(), 1 );
+	}
+
+	#[test]
+	fn once_cancelled_from_inside_its_call() {
+		let drops = Rc::new( Cell::new( 0 ) );
+		let owner: Rc<RefCell<Option<Once>>> = Rc::new( RefCell::new( None ) );
+
+		let guard = DropCounter ( drops.clone() );
+		let closure_owner = owner.clone();
+		let closure = move || {
+			let _ = &guard;
+
+			// Like an operation that is cancelled, and dropped, from within its own callback.
+			let once = closure_owner.borrow_mut().take().unwrap();
+			assert!( unsafe { once.cancel() } );
+			drop( once );
+		};
+
+		let call = borrower( &closure );
+
+		let once = Once::new( closure );
+		let cls = once.cls();
+		*owner.borrow_mut() = Some( once );
+
+		assert!( unsafe { call( cls ) }.is_some() );
+
+		// The closure is dropped after its call, instead of being put back.
+		assert_eq!( drops.get(), 1 );
+		assert!( owner.borrow().is_none() );
+	}
+}
@@ -1,38 +1,480 @@
 use gnunet_sys::*;
 
-use std::ptr;
+use std::{
+	borrow::{Borrow, BorrowMut, ToOwned},
+	error,
+	ffi::{CStr, CString},
+	fmt,
+	ops::{Deref, DerefMut},
+	os::{raw::*, unix::ffi::OsStrExt},
+	path::{Path, PathBuf},
+	ptr::{self, NonNull},
+	slice,
+	time::Duration
+};
 
 
 
-#[derive(Clone, Copy)]
-pub struct Handle ( pub (in crate) *const GNUNET_CONFIGURATION_Handle );
+/// An owned configuration, which is destroyed when dropped.
+pub struct Configuration ( NonNull<GNUNET_CONFIGURATION_Handle> );
 
-unsafe impl Send for Handle {}
-unsafe impl Sync for Handle {}
+/// A borrowed configuration.
+/// This is what `&Configuration` dereferences to, and what is given to callbacks of which Gnunet owns the configuration.
+#[repr(transparent)]
+pub struct ConfigurationRef ( GNUNET_CONFIGURATION_Handle );
+
+unsafe impl Send for Configuration {}
+unsafe impl Sync for Configuration {}
+// Modifying a configuration requires a mutable reference, so sharing immutable ones is fine.
+unsafe impl Sync for ConfigurationRef {}
+
+#[derive(Debug)]
+pub enum Error {
+	/// The option doesn't exist in the given section.
+	NotFound,
+	/// The value of the option can not be interpreted as the requested type.
+	InvalidValue,
+	/// The value of the option is not one of the given choices.
+	InvalidChoice( String ),
+	/// The given file could not be loaded, parsed or written.
+	File( PathBuf ),
+	/// The serialized configuration could not be parsed.
+	Syntax
+}
 
 
 
-impl Handle {
+impl Configuration {
 
-	pub fn create() -> Self {
+	/// Creates an empty configuration.
+	pub fn new() -> Self {
 		let inner = unsafe { GNUNET_CONFIGURATION_create() };
-		assert!( inner != ptr::null_mut(), "inner handle cannot be null" );
 
 		Self (
-			inner
+			NonNull::new( inner ).expect("inner handle cannot be null")
 		)
 	}
 
-	pub fn from_inner( inner: *const GNUNET_CONFIGURATION_Handle ) -> Self {
+	/// Takes ownership of a configuration created by Gnunet.
+	///
+	/// # Safety
+	/// `inner` must be a valid configuration handle that nothing else will destroy.
+	pub unsafe fn from_inner( inner: *mut GNUNET_CONFIGURATION_Handle ) -> Self {
 		Self (
-			inner
+			NonNull::new( inner ).expect("inner handle cannot be null")
 		)
 	}
 }
 
-impl Default for Handle {
+impl ConfigurationRef {
+
+	/// Borrows a configuration owned by Gnunet.
+	///
+	/// # Safety
+	/// `inner` must be a valid configuration handle that outlives `'a`.
+	pub unsafe fn from_ptr<'a>( inner: *const GNUNET_CONFIGURATION_Handle ) -> &'a Self {
+		assert!( inner != ptr::null(), "inner handle cannot be null" );
+		&*(inner as *const Self)
+	}
+
+	pub fn as_ptr( &self ) -> *const GNUNET_CONFIGURATION_Handle {
+		&self.0 as _
+	}
+
+	pub fn as_mut_ptr( &mut self ) -> *mut GNUNET_CONFIGURATION_Handle {
+		&mut self.0 as _
+	}
+
+	/// Loads the configuration from the serialized form given by `serialize`.
+	pub fn deserialize( &mut self, data: &str ) -> Result<(), Error> {
+		let result = unsafe { GNUNET_CONFIGURATION_deserialize( self.as_mut_ptr(), data.as_ptr() as _, data.len() as _, ptr::null() ) };
+		if result != GNUNET_GenericReturnValue_GNUNET_OK { return Err( Error::Syntax ) }
+		Ok(())
+	}
+
+	/// Creates a new configuration that only contains the options of `new` that differ from the ones in `self`.
+	pub fn diff( &self, new: &ConfigurationRef ) -> Configuration {
+		unsafe { Configuration::from_inner( GNUNET_CONFIGURATION_get_diff( self.as_ptr(), new.as_ptr() ) ) }
+	}
+
+	/// Returns the value of the option, in which `$VAR` variables are expanded and `~` is replaced by the home directory.
+	pub fn get_filename( &self, section: &str, option: &str ) -> Result<PathBuf, Error> {
+		let ( csection, coption ) = section_and_option( section, option );
+		let mut value: *mut c_char = ptr::null_mut();
+
+		let result = unsafe { GNUNET_CONFIGURATION_get_value_filename( self.as_ptr(), csection.as_ptr(), coption.as_ptr(), &mut value as _ ) };
+		self.check( result, &csection, &coption )?;
+
+		Ok( PathBuf::from( unsafe { take_string( value ) } ) )
+	}
+
+	pub fn get_number( &self, section: &str, option: &str ) -> Result<u64, Error> {
+		let ( csection, coption ) = section_and_option( section, option );
+		let mut value: c_ulonglong = 0;
+
+		let result = unsafe { GNUNET_CONFIGURATION_get_value_number( self.as_ptr(), csection.as_ptr(), coption.as_ptr(), &mut value as _ ) };
+		self.check( result, &csection, &coption )?;
+
+		Ok( value as _ )
+	}
+
+	/// Returns which one of the given `choices` is the value of the option.
+	pub fn get_choice<'a>( &self, section: &str, option: &str, choices: &[&'a str] ) -> Result<&'a str, Error> {
+		let ( csection, coption ) = section_and_option( section, option );
+		let cchoices: Vec<CString> = choices.iter().map( |c| CString::new(*c).expect("null character in choice") ).collect();
+		let mut choice_ptrs: Vec<*const c_char> = cchoices.iter().map( |c| c.as_ptr() ).collect();
+		choice_ptrs.push( ptr::null() );
+		let mut value: *const c_char = ptr::null();
+
+		let result = unsafe { GNUNET_CONFIGURATION_get_value_choice( self.as_ptr(), csection.as_ptr(), coption.as_ptr(), choice_ptrs.as_ptr(), &mut value as _ ) };
+		if result != GNUNET_GenericReturnValue_GNUNET_OK {
+			return match self.get_string( section, option ) {
+				Ok( value ) => Err( Error::InvalidChoice( value ) ),
+				Err( e ) => Err( e )
+			}
+		}
+
+		// The returned value is one of the pointers that we've given it.
+		let index = choice_ptrs.iter().position( |c| *c == value ).expect("unknown choice returned");
+		Ok( choices[ index ] )
+	}
+
+	/// Returns the size of the option, which may have a unit like "MiB".
+	pub fn get_size( &self, section: &str, option: &str ) -> Result<u64, Error> {
+		let ( csection, coption ) = section_and_option( section, option );
+		let mut value: c_ulonglong = 0;
+
+		let result = unsafe { GNUNET_CONFIGURATION_get_value_size( self.as_ptr(), csection.as_ptr(), coption.as_ptr(), &mut value as _ ) };
+		self.check( result, &csection, &coption )?;
+
+		Ok( value as _ )
+	}
+
+	pub fn get_string( &self, section: &str, option: &str ) -> Result<String, Error> {
+		let ( csection, coption ) = section_and_option( section, option );
+		let mut value: *mut c_char = ptr::null_mut();
+
+		let result = unsafe { GNUNET_CONFIGURATION_get_value_string( self.as_ptr(), csection.as_ptr(), coption.as_ptr(), &mut value as _ ) };
+		self.check( result, &csection, &coption )?;
+
+		Ok( unsafe { take_string( value ) } )
+	}
+
+	/// Returns the relative time of the option, which may have a unit like "5 s".
+	/// The value "forever" results in the maximum duration.
+	pub fn get_time( &self, section: &str, option: &str ) -> Result<Duration, Error> {
+		let ( csection, coption ) = section_and_option( section, option );
+		let mut value = GNUNET_TIME_Relative { rel_value_us: 0 };
+
+		let result = unsafe { GNUNET_CONFIGURATION_get_value_time( self.as_ptr(), csection.as_ptr(), coption.as_ptr(), &mut value as _ ) };
+		self.check( result, &csection, &coption )?;
+
+		Ok( Duration::from_micros( value.rel_value_us ) )
+	}
+
+	pub fn get_yesno( &self, section: &str, option: &str ) -> Result<bool, Error> {
+		let ( csection, coption ) = section_and_option( section, option );
+
+		let result = unsafe { GNUNET_CONFIGURATION_get_value_yesno( self.as_ptr(), csection.as_ptr(), coption.as_ptr() ) };
+		match result {
+			GNUNET_GenericReturnValue_GNUNET_YES => Ok( true ),
+			GNUNET_GenericReturnValue_GNUNET_NO => Ok( false ),
+			_ => Err( self.error( &csection, &coption ) )
+		}
+	}
+
+	pub fn has_value( &self, section: &str, option: &str ) -> bool {
+		let ( csection, coption ) = section_and_option( section, option );
+
+		unsafe { GNUNET_CONFIGURATION_have_value( self.as_ptr(), csection.as_ptr(), coption.as_ptr() ) == GNUNET_GenericReturnValue_GNUNET_YES }
+	}
+
+	/// Loads the default configuration, and then the given file on top of it.
+	pub fn load( &mut self, filename: Option<&Path> ) -> Result<(), Error> {
+		let cfilename = filename.map( path_to_cstring );
+		let ptr = cfilename.as_ref().map( |f| f.as_ptr() ).unwrap_or( ptr::null() );
+
+		let result = unsafe { GNUNET_CONFIGURATION_load( self.as_mut_ptr(), ptr ) };
+		if result != GNUNET_GenericReturnValue_GNUNET_OK {
+			return Err( Error::File( filename.map( |f| f.to_owned() ).unwrap_or_default() ) )
+		}
+		Ok(())
+	}
+
+	/// Lists all options and their values in the given section.
+	pub fn options( &self, section: &str ) -> Vec<(String, String)> {
+		let csection = CString::new(section).expect("null character in section");
+		let mut options: Vec<(String, String)> = Vec::new();
+
+		unsafe { GNUNET_CONFIGURATION_iterate_section_values( self.as_ptr(), csection.as_ptr(), Some( ffi_option_iterator ), &mut options as *mut _ as _ ) };
+		options
+	}
+
+	/// Parses the given file, without loading the defaults first.
+	pub fn parse( &mut self, filename: &Path ) -> Result<(), Error> {
+		let cfilename = path_to_cstring( filename );
+
+		let result = unsafe { GNUNET_CONFIGURATION_parse( self.as_mut_ptr(), cfilename.as_ptr() ) };
+		if result != GNUNET_GenericReturnValue_GNUNET_OK {
+			return Err( Error::File( filename.to_owned() ) )
+		}
+		Ok(())
+	}
+
+	/// Lists the names of all sections.
+	pub fn sections( &self ) -> Vec<String> {
+		let mut sections: Vec<String> = Vec::new();
+
+		unsafe { GNUNET_CONFIGURATION_iterate_sections( self.as_ptr(), Some( ffi_section_iterator ), &mut sections as *mut _ as _ ) };
+		sections
+	}
+
+	/// Converts the configuration into the format of a configuration file.
+	pub fn serialize( &self ) -> String {
+		unsafe {
+			let mut size: usize = 0;
+			let data = GNUNET_CONFIGURATION_serialize( self.as_ptr(), &mut size as *mut usize as _ );
+			let result = String::from_utf8_lossy( slice::from_raw_parts( data as *const u8, size ) ).into_owned();
+
+			GNUNET_free( data as _ );
+			result
+		}
+	}
+
+	/// Sets the option to one of the given `choices`, so that `get_choice` with the same choices returns it.
+	/// Returns `Error::InvalidChoice` without changing the option when `value` is not one of the choices.
+	pub fn set_choice( &mut self, section: &str, option: &str, choices: &[&str], value: &str ) -> Result<(), Error> {
+		if !choices.contains( &value ) {
+			return Err( Error::InvalidChoice( value.to_owned() ) )
+		}
+
+		self.set_string( section, option, value );
+		Ok(())
+	}
+
+	pub fn set_filename( &mut self, section: &str, option: &str, value: &Path ) {
+		let ( csection, coption ) = section_and_option( section, option );
+		let cvalue = path_to_cstring( value );
+
+		unsafe { GNUNET_CONFIGURATION_set_value_string( self.as_mut_ptr(), csection.as_ptr(), coption.as_ptr(), cvalue.as_ptr() ) };
+	}
+
+	pub fn set_number( &mut self, section: &str, option: &str, value: u64 ) {
+		let ( csection, coption ) = section_and_option( section, option );
+
+		unsafe { GNUNET_CONFIGURATION_set_value_number( self.as_mut_ptr(), csection.as_ptr(), coption.as_ptr(), value as _ ) };
+	}
+
+	/// Sets the size of the option, in bytes.
+	pub fn set_size( &mut self, section: &str, option: &str, value: u64 ) {
+		self.set_string( section, option, &format!( "{} B", value ) );
+	}
+
+	pub fn set_string( &mut self, section: &str, option: &str, value: &str ) {
+		let ( csection, coption ) = section_and_option( section, option );
+		let cvalue = CString::new(value).expect("null character in value");
+
+		unsafe { GNUNET_CONFIGURATION_set_value_string( self.as_mut_ptr(), csection.as_ptr(), coption.as_ptr(), cvalue.as_ptr() ) };
+	}
+
+	/// Sets the relative time of the option, in the format that `get_time` reads.
+	/// Durations that don't fit in Gnunet's relative time become "forever".
+	pub fn set_time( &mut self, section: &str, option: &str, value: Duration ) {
+		let ( csection, coption ) = section_and_option( section, option );
+		let relative = GNUNET_TIME_Relative { rel_value_us: value.as_micros().min( u64::MAX as u128 ) as u64 };
+
+		// The string is statically allocated by Gnunet, and is copied by setting it.
+		unsafe {
+			let cvalue = GNUNET_STRINGS_relative_time_to_string( relative, GNUNET_GenericReturnValue_GNUNET_NO as _ );
+			GNUNET_CONFIGURATION_set_value_string( self.as_mut_ptr(), csection.as_ptr(), coption.as_ptr(), cvalue );
+		}
+	}
+
+	pub fn set_yesno( &mut self, section: &str, option: &str, value: bool ) {
+		self.set_string( section, option, if value { "YES" } else { "NO" } );
+	}
+
+	/// Writes the configuration to a file.
+	/// This takes `&mut self`, because Gnunet marks the configuration as no longer being changed since it was written.
+	pub fn write( &mut self, filename: &Path ) -> Result<(), Error> {
+		let cfilename = path_to_cstring( filename );
+
+		let result = unsafe { GNUNET_CONFIGURATION_write( self.as_mut_ptr(), cfilename.as_ptr() ) };
+		if result != GNUNET_GenericReturnValue_GNUNET_OK {
+			return Err( Error::File( filename.to_owned() ) )
+		}
+		Ok(())
+	}
+
+	/// Writes only the options of `self` that differ from `default` to a file.
+	pub fn write_diffs( &self, default: &ConfigurationRef, filename: &Path ) -> Result<(), Error> {
+		let cfilename = path_to_cstring( filename );
+
+		let result = unsafe { GNUNET_CONFIGURATION_write_diffs( default.as_ptr(), self.as_ptr(), cfilename.as_ptr() ) };
+		if result != GNUNET_GenericReturnValue_GNUNET_OK {
+			return Err( Error::File( filename.to_owned() ) )
+		}
+		Ok(())
+	}
+
+	fn check( &self, result: GNUNET_GenericReturnValue, section: &CStr, option: &CStr ) -> Result<(), Error> {
+		if result == GNUNET_GenericReturnValue_GNUNET_OK {
+			Ok(())
+		}
+		else {
+			Err( self.error( section, option ) )
+		}
+	}
+
+	/// The getters don't tell why they failed, so we check whether the option exists to find out.
+	fn error( &self, section: &CStr, option: &CStr ) -> Error {
+		let exists = unsafe { GNUNET_CONFIGURATION_have_value( self.as_ptr(), section.as_ptr(), option.as_ptr() ) };
+
+		if exists == GNUNET_GenericReturnValue_GNUNET_YES { Error::InvalidValue } else { Error::NotFound }
+	}
+}
+
+impl AsRef<ConfigurationRef> for Configuration {
+
+	fn as_ref( &self ) -> &ConfigurationRef {
+		self
+	}
+}
+
+impl Borrow<ConfigurationRef> for Configuration {
+
+	fn borrow( &self ) -> &ConfigurationRef {
+		self
+	}
+}
+
+impl BorrowMut<ConfigurationRef> for Configuration {
+
+	fn borrow_mut( &mut self ) -> &mut ConfigurationRef {
+		self
+	}
+}
+
+impl Clone for Configuration {
+
+	fn clone( &self ) -> Self {
+		(**self).to_owned()
+	}
+}
+
+impl Default for Configuration {
 
 	fn default() -> Self {
-		Self ( ptr::null() )
+		Self::new()
 	}
-}
\ No newline at end of file
+}
+
+impl Deref for Configuration {
+	type Target = ConfigurationRef;
+
+	fn deref( &self ) -> &ConfigurationRef {
+		unsafe { ConfigurationRef::from_ptr( self.0.as_ptr() ) }
+	}
+}
+
+impl DerefMut for Configuration {
+
+	fn deref_mut( &mut self ) -> &mut ConfigurationRef {
+		unsafe { &mut *(self.0.as_ptr() as *mut ConfigurationRef) }
+	}
+}
+
+impl Drop for Configuration {
+
+	fn drop( &mut self ) {
+		unsafe { GNUNET_CONFIGURATION_destroy( self.0.as_ptr() ) };
+	}
+}
+
+impl ToOwned for ConfigurationRef {
+	type Owned = Configuration;
+
+	fn to_owned( &self ) -> Configuration {
+		unsafe { Configuration::from_inner( GNUNET_CONFIGURATION_dup( self.as_ptr() ) ) }
+	}
+}
+
+impl fmt::Display for Error {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::NotFound => write!(f, "option not found"),
+			Error::InvalidValue => write!(f, "invalid value for option"),
+			Error::InvalidChoice( value ) => write!(f, "\"{}\" is not a valid choice for option", value ),
+			Error::File( filename ) => write!(f, "unable to process configuration file {}", filename.display() ),
+			Error::Syntax => write!(f, "syntax error in configuration")
+		}
+	}
+}
+
+impl error::Error for Error {}
+
+
+
+fn path_to_cstring( path: &Path ) -> CString {
+	CString::new( path.as_os_str().as_bytes() ).expect("null character in filename")
+}
+
+fn section_and_option( section: &str, option: &str ) -> (CString, CString) {
+	(
+		CString::new(section).expect("null character in section"),
+		CString::new(option).expect("null character in option")
+	)
+}
+
+/// Copies a string that has been allocated by Gnunet, and frees it.
+unsafe fn take_string( ptr: *mut c_char ) -> String {
+	let result = CStr::from_ptr( ptr ).to_string_lossy().into_owned();
+	GNUNET_free( ptr as _ );
+	result
+}
+
+
+
+unsafe extern "C" fn ffi_option_iterator( cls: *mut c_void, _section: *const c_char, option: *const c_char, value: *const c_char ) {
+	let options = &mut *(cls as *mut Vec<(String, String)>);
+
+	options.push( (
+		CStr::from_ptr( option ).to_string_lossy().into_owned(),
+		CStr::from_ptr( value ).to_string_lossy().into_owned()
+	) );
+}
+
+unsafe extern "C" fn ffi_section_iterator( cls: *mut c_void, section: *const c_char ) {
+	let sections = &mut *(cls as *mut Vec<String>);
+
+	sections.push( CStr::from_ptr( section ).to_string_lossy().into_owned() );
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CHOICES: &[&str] = &[ "tcp", "udp" ];
+
+	#[test]
+	fn valid_choices_are_set() {
+		let mut config = Configuration::new();
+
+		config.set_choice( "transport", "protocol", CHOICES, "udp" ).unwrap();
+		assert_eq!( config.get_choice( "transport", "protocol", CHOICES ).unwrap(), "udp" );
+	}
+
+	#[test]
+	fn invalid_choices_are_rejected() {
+		let mut config = Configuration::new();
+
+		match config.set_choice( "transport", "protocol", CHOICES, "http" ) {
+			Err( Error::InvalidChoice( value ) ) => assert_eq!( value, "http" ),
+			other => panic!( "unexpected result {:?}", other )
+		}
+		assert!( matches!( config.get_string( "transport", "protocol" ), Err( Error::NotFound ) ) );
+	}
+}
@@ -1,58 +1,115 @@
 use gnunet_sys::*;
 
+use std::{
+	os::raw::*,
+	ptr
+};
 
+use crate::closure;
+use crate::configuration::ConfigurationRef;
+use crate::crypto::PeerIdentity;
+use crate::mq;
 
+
+
+/// A connection to the CORE service, which notifies about peers that we are directly connected with.
 pub struct Handle {
-	inner: *mut GNUNET_CORE_Handler
+	inner: *mut GNUNET_CORE_Handle,
+	closures: closure::Repeating
+}
+
+struct ClosureData {
+	on_init: Option<Box<dyn FnOnce( PeerIdentity )>>,
+	on_connect: Box<dyn FnMut( &PeerIdentity, mq::Handle )>,
+	on_disconnect: Box<dyn FnMut( &PeerIdentity )>,
+	handlers: mq::MessageHandlers
 }
 
 
 
 impl Handle {
 
-	pub fn connect<S,C,D>( on_done: S, on_connect: C, on_disconnect: D ) -> Self where
-		S: FnOnce( PeerIdentity ),
-		C: FnMut( PeerIdentity, mq::Handle ),
-		D: FnMut( PeerIdentity )
+	/// Connects to the CORE service.
+	///
+	/// # Arguments
+	/// * `config` - the configuration to use
+	/// * `handlers` - the handlers for the messages that peers send us
+	/// * `on_init` - called with our own peer identity once the connection has been established
+	/// * `on_connect` - called for every peer that connects, with a message queue to send messages to that peer
+	/// * `on_disconnect` - called for every peer that disconnects, after which its message queue is not valid anymore
+	pub fn connect<S,C,D>( config: &ConfigurationRef, handlers: mq::MessageHandlers, on_init: S, on_connect: C, on_disconnect: D ) -> Self where
+		S: FnOnce( PeerIdentity ) + 'static,
+		C: FnMut( &PeerIdentity, mq::Handle ) + 'static,
+		D: FnMut( &PeerIdentity ) + 'static
 	{
-		let inner = unsafe{ GNUNET_CORE_connect( ptr::null(), ptr::null_mut(), Some(ffi_done_handler), Some(ffi_connect_handler), Some(ffi_disconnect_handler) ) };
+		// The handlers get copied by the service.
+		let chandlers = handlers.to_array();
+
+		let closures = closure::Repeating::new( ClosureData {
+			on_init: Some( Box::new( on_init ) ),
+			on_connect: Box::new( on_connect ),
+			on_disconnect: Box::new( on_disconnect ),
+			handlers
+		} );
+
+		let inner = unsafe { GNUNET_CORE_connect(
+			config.as_ptr(),
+			closures.cls(),
+			Some( ffi_init_handler ),
+			Some( ffi_connect_handler ),
+			Some( ffi_disconnect_handler ),
+			chandlers.as_ptr()
+		) };
+		assert!( inner != ptr::null_mut(), "unable to connect to core service" );
 
 		Self {
-			inner
+			inner,
+			closures
 		}
 	}
 
-	pub fn disconnect( &mut self ) {
+	/// Disconnects from the CORE service.
+	/// This is the same as dropping the handle.
+	pub fn disconnect( self ) {}
+}
+
+impl Drop for Handle {
+
+	/// The closures are freed after disconnecting, when no callbacks can be called anymore.
+	fn drop( &mut self ) {
 		unsafe { GNUNET_CORE_disconnect( self.inner ) };
 	}
 }
 
 
 
-unsafe extern "C" fn ffi_connect_handler<C>( data: *mut c_void, _my_id: *const GNUNET_PeerIdentity, queue: *const GNUNET_MQ_Handle ) where
-	C: FnOnce(PeerIdentity, mq::Handle)
-{
-
-	let on_connect: Box<C> = Box::from_raw( data as _ );
-	let my_id = PeerIdentity::from_inner( _myid );
+unsafe extern "C" fn ffi_connect_handler( cls: *mut c_void, peer: *const GNUNET_PeerIdentity, queue: *mut GNUNET_MQ_Handle ) -> *mut c_void {
+	let data: &mut ClosureData = closure::Repeating::get( cls );
+	let peer = PeerIdentity::from_inner( *peer );
 	let mq = mq::Handle::from_inner( queue );
-	on_done( my_id, mq )
+
+	(data.on_connect)( &peer, mq );
+
+	// This becomes the closure of the message handlers for this peer.
+	data.handlers.context_ptr() as _
 }
 
-unsafe extern "C" fn ffi_disconnect_handler<D>( data: *mut c_void, _my_id: *const GNUNET_PeerIdentity ) 
-	D: FnOnce(PeerIdentity)
-{
+unsafe extern "C" fn ffi_disconnect_handler( cls: *mut c_void, peer: *const GNUNET_PeerIdentity, _peer_cls: *mut c_void ) {
+	let data: &mut ClosureData = closure::Repeating::get( cls );
+	let peer = PeerIdentity::from_inner( *peer );
 
-	let on_disconnect: Box<D> = Box::from_raw( data as _ );
-	let my_id = PeerIdentity::from_inner( _my_id );
-	on_done( my_id )
+	(data.on_disconnect)( &peer );
 }
 
-unsafe extern "C" fn ffi_done_handler<S>( data: *mut c_void, _my_id: *const GNUNET_PeerIdentity )
-	S: FnOnce(PeerIdentity)
-{
+unsafe extern "C" fn ffi_init_handler( cls: *mut c_void, my_identity: *const GNUNET_PeerIdentity ) {
+	let data: &mut ClosureData = closure::Repeating::get( cls );
 
-	let on_done: Box<S> = Box::from_raw( data as _ );
-	let my_id = PeerIdentity::from_inner( _my_id );
-	on_done( my_id )
-}
\ No newline at end of file
+	// The identity is NULL when the connection to the service failed.
+	if my_identity == ptr::null() {
+		return
+	}
+
+	if let Some( on_init ) = data.on_init.take() {
+		on_init( PeerIdentity::from_inner( *my_identity ) );
+	}
+}
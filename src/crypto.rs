@@ -11,6 +11,7 @@ use gnunet_sys::*;
 #[derive(Clone)]
 pub struct HashCode ( pub(in crate) GNUNET_HashCode );
 
+#[derive(Clone)]
 pub struct PeerIdentity (
 	pub (in crate) GNUNET_PeerIdentity
 );
@@ -47,6 +48,15 @@ impl PeerIdentity {
 	}
 }
 
+impl Eq for PeerIdentity {}
+
+impl PartialEq for PeerIdentity {
+
+	fn eq( &self, other: &Self ) -> bool {
+		self.0.public_key.q_y == other.0.public_key.q_y
+	}
+}
+
 
 
 impl HashCode {
@@ -77,6 +87,18 @@ impl HashCode {
 		hash
 	}
 
+	/// Like `from_string`, but returns `None` if the string isn't a properly encoded hash.
+	pub fn try_from_string( string: &str ) -> Option<Self> {
+		let cstring = CString::new(string).ok()?;
+		let mut hash = Self::new();
+
+		let result = unsafe { GNUNET_CRYPTO_hash_from_string2( cstring.as_ptr(), string.len() as _, &mut hash.0 as _ ) };
+		if result != GNUNET_GenericReturnValue_GNUNET_OK {
+			return None
+		}
+		Some( hash )
+	}
+
 	pub fn generate( data: &[u8] ) -> Self {
 		let mut hash = Self::new();
 
@@ -23,6 +23,13 @@ impl MsgError {
 
 		x
 	}
+
+	/// An error that didn't come from Gnunet.
+	pub(in crate) fn from_message( msg: &str ) -> Self {
+		Self {
+			msg: msg.to_owned()
+		}
+	}
 }
 
 impl fmt::Display for MsgError {
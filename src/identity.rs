@@ -1,14 +1,27 @@
 use gnunet_sys::*;
 
 use std::{
+	cell::RefCell,
+	collections::{HashMap, VecDeque},
+	error, fmt,
 	ffi::*,
-	mem::MaybeUninit,
+	future::Future,
+	marker::PhantomData,
+	mem::{self, MaybeUninit},
 	os::raw::*,
-	ptr
+	pin::Pin,
+	ptr,
+	rc::Rc,
+	slice,
+	str::FromStr,
+	task::{Context, Poll, Waker}
 };
 
+use futures::Stream;
+
 use crate::{
-	configuration,
+	closure,
+	configuration::ConfigurationRef,
 	crypto::HashCode,
 	error::*,
 	future::*
@@ -16,22 +29,90 @@ use crate::{
 
 
 
+/// An ego of the identity service.
+/// It is only valid during the callback that it is given to, use `to_info` to keep it around.
 pub struct Ego ( *mut GNUNET_IDENTITY_Ego );
-pub struct Handle ( *mut GNUNET_IDENTITY_Handle );
+/// An owned copy of an ego.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EgoInfo {
+	pub name: String,
+	pub public_key: PublicKey,
+	pub private_key: PrivateKey
+}
+/// A change to the egos of the identity service.
+#[derive(Clone, Debug)]
+pub enum EgoEvent {
+	Added { name: String, public_key: PublicKey },
+	Renamed { old_name: String, new_name: String, public_key: PublicKey },
+	Removed { name: String, public_key: PublicKey },
+	/// All egos that existed when connecting have been added.
+	InitialListComplete
+}
+/// The egos that the identity service has told us about, and the streams that watch them.
+#[derive(Default)]
+struct EgoList {
+	// Egos by the address of their handle, which stays the same until they are removed.
+	egos: HashMap<usize, (String, PublicKey)>,
+	complete: bool,
+	watchers: Vec<Rc<RefCell<WatchState>>>
+}
+struct EgoCallbacks {
+	on_ego: Option<Box<IdentityCallback>>,
+	list: Rc<RefCell<EgoList>>
+}
+pub struct Handle {
+	inner: *mut GNUNET_IDENTITY_Handle,
+	egos: Rc<RefCell<EgoList>>,
+	_callbacks: closure::Repeating
+}
 pub type IdentityCallback = dyn FnMut(Ego, &str, &'static mut *mut ());
-pub struct Operation ( *mut GNUNET_IDENTITY_Operation );
-pub struct PrivateKeyHandle ( *mut GNUNET_IDENTITY_PrivateKey );
+/// An operation of the identity service, which can't outlive the handle that it has been started on.
+pub struct Operation<'h> {
+	inner: *mut GNUNET_IDENTITY_Operation,
+	closure: closure::Once,
+	_handle: PhantomData<&'h Handle>
+}
+/// A future of the result of an operation, which has been started already.
+/// Dropping it before the operation has completed cancels the operation.
+pub struct OperationFuture<'h, T> {
+	operation: Option<Operation<'h>>,
+	state: Rc<RefCell<OperationState<T>>>
+}
+struct OperationState<T> {
+	result: Option<T>,
+	waker: Option<Waker>
+}
+/// An error for keys and signatures that could not be parsed.
+#[derive(Debug)]
+pub struct InvalidKeyError;
+/// A private key, which is zeroed when dropped.
+#[derive(Clone)]
+pub struct PrivateKey ( GNUNET_IDENTITY_PrivateKey );
+#[derive(Clone)]
 pub struct PublicKey ( GNUNET_IDENTITY_PublicKey );
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum KeyType {
 	Ecdsa,
 	Eddsa
 }
+#[derive(Clone)]
+pub struct Signature ( GNUNET_IDENTITY_Signature );
+/// The size of a private key written by `PrivateKey::to_bytes`, which is the same for both key types.
+const PRIVATE_KEY_SIZE: usize = 4 + 32;
+/// A stream of the changes to the egos of the identity service.
+/// It ends when the handle is dropped.
+pub struct Watch {
+	state: Rc<RefCell<WatchState>>
+}
+#[derive(Default)]
+struct WatchState {
+	events: VecDeque<EgoEvent>,
+	closed: bool,
+	waker: Option<Waker>
+}
 
 
-unsafe impl Send for Ego {}
-unsafe impl Send for Handle {}
-unsafe impl Send for Operation {}
-unsafe impl Send for PrivateKeyHandle {}
 
 
 impl Ego {
@@ -42,6 +123,10 @@ impl Ego {
 		Self ( inner )
 	}
 
+	pub fn get_private_key( &self ) -> PrivateKey {
+		unsafe { PrivateKey ( *GNUNET_IDENTITY_ego_get_private_key( self.0 ) ) }
+	}
+
 	pub fn get_public_key( &self ) -> PublicKey {
 		unsafe {
 			let mut public_key: PublicKey = MaybeUninit::uninit().assume_init();
@@ -52,19 +137,32 @@ impl Ego {
 		}
 	}
 
-	pub fn lookup<C>( config: &configuration::Handle, name: &str, callback: C ) where
-		C: FnOnce( Option<Ego> )
+	/// Copies the keys of the ego, together with its name.
+	pub fn to_info( &self, name: &str ) -> EgoInfo {
+		EgoInfo {
+			name: name.to_owned(),
+			public_key: self.get_public_key(),
+			private_key: self.get_private_key()
+		}
+	}
+
+	pub fn lookup<C>( config: &ConfigurationRef, name: &str, callback: C ) where
+		C: FnOnce( Option<Ego> ) + 'static
 	{
 		let cname = CString::new(name).expect("null character in `name`");
-		let cls = Box::into_raw( Box::new( callback ) );
+		let closure = closure::Once::new( callback );
 
-		unsafe { GNUNET_IDENTITY_ego_lookup( config.0, cname.as_ptr(), Some( ffi_lookup_callback::<C> ), cls as _ ) };
+		unsafe { GNUNET_IDENTITY_ego_lookup( config.as_ptr(), cname.as_ptr(), Some( ffi_lookup_callback::<C> ), closure.cls() ) };
 	}
 
-	pub async fn lookup_async( config: &configuration::Handle, name: &str ) -> Option<Ego> {
+	/// Looks up the ego with the given name.
+	/// Because the ego itself is only valid during the callback, this resolves to a copy of it.
+	pub async fn lookup_async( config: &ConfigurationRef, name: &str ) -> Option<EgoInfo> {
+		let owned_name = name.to_owned();
+
 		CallbackFuture::new(|wake| {
-			Self::lookup( config, name, |result| {
-				wake( result );
+			Self::lookup( config, name, move |result| {
+				wake( result.map( |ego| ego.to_info( &owned_name ) ) );
 			})
 		}).await
 	}
@@ -72,26 +170,33 @@ impl Ego {
 
 impl Handle {
 
-	/// Connects to the identity service, and gives all available ego's through `on_ego`.
-	/// 
-	/// *Warning*: Currently there is a bug which makes other functions assert if you connected to the identity service with this.
-	///            For the time being, use `connect_and_list( &config, |_,_,_|{})` instead.
-	pub fn connect( config: &configuration::Handle ) -> Self {
-		
-		let inner = unsafe { GNUNET_IDENTITY_connect( config.0, None, ptr::null_mut() ) };
-		assert!(inner != ptr::null_mut(), "unable to connect to identity service");
-		Self ( inner )
+	/// Connects to the identity service.
+	pub fn connect( config: &ConfigurationRef ) -> Self {
+		Self::connect_inner( config, None )
 	}
 
 	/// Connects to the identity service, and gives all available ego's through `on_ego`.
-	pub fn connect_and_list<C>( config: &configuration::Handle, on_ego: C ) -> Self where
-		C: FnMut(Ego, &str, &'static mut *mut ())
+	/// Afterwards, `on_ego` is called for every ego that is added or renamed.
+	pub fn connect_and_list<C>( config: &ConfigurationRef, on_ego: C ) -> Self where
+		C: FnMut(Ego, &str, &'static mut *mut ()) + 'static
 	{
-		let cls = Box::into_raw( Box::new( on_ego ) );
-		
-		let inner = unsafe { GNUNET_IDENTITY_connect( config.0, Some( ffi_identity_callback::<C> ), cls as _ ) };
+		Self::connect_inner( config, Some( Box::new( on_ego ) ) )
+	}
+
+	fn connect_inner( config: &ConfigurationRef, on_ego: Option<Box<IdentityCallback>> ) -> Self {
+		let egos = Rc::new( RefCell::new( EgoList::default() ) );
+		let callbacks = closure::Repeating::new( EgoCallbacks {
+			on_ego,
+			list: egos.clone()
+		} );
+
+		let inner = unsafe { GNUNET_IDENTITY_connect( config.as_ptr(), Some( ffi_identity_callback ), callbacks.cls() ) };
 		assert!(inner != ptr::null_mut(), "unable to connect to identity service");
-		Self ( inner )
+		Self {
+			inner,
+			egos,
+			_callbacks: callbacks
+		}
 	}
 
 	/// Create a new ego with the given name.
@@ -99,27 +204,24 @@ impl Handle {
 	/// # Arguments
 	/// * `name` - desired name
 	/// * `private_key` - desired private key, or `None` to create one
-	/// * `key_type` - the type of key to create. Ignored if `private_key` is `None`.
+	/// * `key_type` - the type of key to create. Ignored if `private_key` is given, because that has a type of its own.
 	/// * `on_complete` - The closure that will be called with the result
 	/// 
 	/// # Returns
 	/// A handle to abort the operation
-	pub fn create<C>( &mut self, name: &str, private_key: Option<PrivateKeyHandle>, key_type: KeyType, on_complete: C ) -> Operation where
-		C: FnOnce(Result<PrivateKeyHandle, MsgError>)
+	pub fn create<C>( &self, name: &str, private_key: Option<&PrivateKey>, key_type: KeyType, on_complete: C ) -> Operation<'_> where
+		C: FnOnce(Result<PrivateKey, MsgError>) + 'static
 	{
 		let cname = CString::new(name).expect("null character in name");
 		let cprivate_key = match private_key {
-			Some(key) => key.0,
+			Some(key) => &key.0 as _,
 			None => ptr::null(),
 		};
-		let ckey_type = match key_type {
-			KeyType::Ecdsa => GNUNET_IDENTITY_KeyType_GNUNET_IDENTITY_TYPE_ECDSA,
-			KeyType::Eddsa => GNUNET_IDENTITY_KeyType_GNUNET_IDENTITY_TYPE_EDDSA
-		};
-		let cls = Box::into_raw( Box::new( on_complete ) );
-		eprintln!("GNUNET_IDENTITY_create");
-		let inner = unsafe { GNUNET_IDENTITY_create( self.0, cname.as_ptr(), cprivate_key, ckey_type, Some( ffi_create_callback::<C> ), cls as _ ) };
-		Operation( inner )
+		let ckey_type = key_type.to_inner();
+		let closure = closure::Once::new( on_complete );
+
+		let inner = unsafe { GNUNET_IDENTITY_create( self.inner, cname.as_ptr(), cprivate_key, ckey_type, Some( ffi_create_callback::<C> ), closure.cls() ) };
+		Operation { inner, closure, _handle: PhantomData }
 	}
 
 	/// Create a new ego with the given name.
@@ -127,83 +229,650 @@ impl Handle {
 	/// # Arguments
 	/// * `name` - desired name
 	/// * `private_key` - desired private key, or `None` to create one
-	/// * `key_type` - the type of key to create. Ignored if `private_key` is `None`.
-	/// * `on_complete` - The closure that will be called with the result
+	/// * `key_type` - the type of key to create. Ignored if `private_key` is given, because that has a type of its own.
 	/// 
 	/// # Returns
-	/// The new private key (handle), or an error
-	pub async fn create_async( &mut self, name: &str, private_key: Option<PrivateKeyHandle>, key_type: KeyType ) -> Result<PrivateKeyHandle, MsgError> {
+	/// A future of the new private key, or an error, which cancels the operation when dropped early
+	pub fn create_async( &self, name: &str, private_key: Option<&PrivateKey>, key_type: KeyType ) -> OperationFuture<'_, Result<PrivateKey, MsgError>> {
+		OperationFuture::start( |on_complete| self.create( name, private_key, key_type, on_complete ) )
+	}
 
-		CallbackFuture::new(|wake| { eprintln!("callback_future");
-			self.create( name, private_key, key_type, |result| {eprintln!("callback_future2");
-				wake( result );
-			});
-		}).await
+	/// Creates a new ego with the given name, from a key that has been imported with `PrivateKey::from_bytes`.
+	///
+	/// # Returns
+	/// A handle to abort the operation, or an error without starting it when the type of the key is unknown
+	pub fn create_from_key<C>( &self, name: &str, private_key: &PrivateKey, on_complete: C ) -> Result<Operation<'_>, MsgError> where
+		C: FnOnce(Result<PrivateKey, MsgError>) + 'static
+	{
+		let key_type = private_key.key_type().ok_or_else( || MsgError::from_message( "unknown key type" ) )?;
+		Ok( self.create( name, Some( private_key ), key_type, on_complete ) )
+	}
+
+	/// Deletes the ego with the given name.
+	///
+	/// # Returns
+	/// A handle to abort the operation
+	pub fn delete<C>( &self, name: &str, on_complete: C ) -> Operation<'_> where
+		C: FnOnce(Result<(), MsgError>) + 'static
+	{
+		let cname = CString::new(name).expect("null character in name");
+		let closure = closure::Once::new( on_complete );
+
+		let inner = unsafe { GNUNET_IDENTITY_delete( self.inner, cname.as_ptr(), Some( ffi_continuation::<C> ), closure.cls() ) };
+		Operation { inner, closure, _handle: PhantomData }
+	}
+
+	/// Deletes the ego with the given name.
+	/// The operation is cancelled when the future is dropped before it completes.
+	pub fn delete_async( &self, name: &str ) -> OperationFuture<'_, Result<(), MsgError>> {
+		OperationFuture::start( |on_complete| self.delete( name, on_complete ) )
 	}
 
 	/// Obtains a default ego associated with the given service.
-	pub fn default_ego( &mut self, service: &str, callback: impl FnMut(Ego, &str, &'static mut *mut ()) ) -> Operation {
+	pub fn default_ego( &self, service: &str, callback: impl FnMut(Ego, &str, &'static mut *mut ()) + 'static ) -> Operation<'_> {
 		self.get( service, callback )
 	}
 
 	/// Disconnects from the identity service.
-	pub fn disconnect( self ) {
-		unsafe { GNUNET_IDENTITY_disconnect( self.0 ) };
+	/// This is the same as dropping the handle.
+	pub fn disconnect( self ) {}
+
+	/// Renames the ego `old_name` to `new_name`.
+	///
+	/// # Returns
+	/// A handle to abort the operation
+	pub fn rename<C>( &self, old_name: &str, new_name: &str, on_complete: C ) -> Operation<'_> where
+		C: FnOnce(Result<(), MsgError>) + 'static
+	{
+		let cold_name = CString::new(old_name).expect("null character in `old_name`");
+		let cnew_name = CString::new(new_name).expect("null character in `new_name`");
+		let closure = closure::Once::new( on_complete );
+
+		let inner = unsafe { GNUNET_IDENTITY_rename( self.inner, cold_name.as_ptr(), cnew_name.as_ptr(), Some( ffi_continuation::<C> ), closure.cls() ) };
+		Operation { inner, closure, _handle: PhantomData }
+	}
+
+	/// Renames the ego `old_name` to `new_name`.
+	/// The operation is cancelled when the future is dropped before it completes.
+	pub fn rename_async( &self, old_name: &str, new_name: &str ) -> OperationFuture<'_, Result<(), MsgError>> {
+		OperationFuture::start( |on_complete| self.rename( old_name, new_name, on_complete ) )
+	}
+
+	/// Makes `ego` the default ego of the given service.
+	///
+	/// # Returns
+	/// A handle to abort the operation
+	pub fn set<C>( &self, service: &str, ego: &Ego, on_complete: C ) -> Operation<'_> where
+		C: FnOnce(Result<(), MsgError>) + 'static
+	{
+		let cservice = CString::new(service).expect("null character in `service`");
+		let closure = closure::Once::new( on_complete );
+
+		let inner = unsafe { GNUNET_IDENTITY_set( self.inner, cservice.as_ptr(), ego.0, Some( ffi_continuation::<C> ), closure.cls() ) };
+		Operation { inner, closure, _handle: PhantomData }
+	}
+
+	/// Makes `ego` the default ego of the given service.
+	/// The operation is cancelled when the future is dropped before it completes.
+	pub fn set_async( &self, service: &str, ego: &Ego ) -> OperationFuture<'_, Result<(), MsgError>> {
+		OperationFuture::start( |on_complete| self.set( service, ego, on_complete ) )
+	}
+
+	/// Returns a stream of the changes to the egos.
+	/// It starts with the egos that are known already, and with `EgoEvent::InitialListComplete` if the service has listed all of them already.
+	pub fn watch( &self ) -> Watch {
+		let mut state = WatchState::default();
+		let mut list = self.egos.borrow_mut();
+
+		for ( name, public_key ) in list.egos.values() {
+			state.events.push_back( EgoEvent::Added { name: name.clone(), public_key: public_key.clone() } );
+		}
+		if list.complete {
+			state.events.push_back( EgoEvent::InitialListComplete );
+		}
+
+		let state = Rc::new( RefCell::new( state ) );
+		list.watchers.push( state.clone() );
+		Watch { state }
 	}
 
 	/// Same as `default_ego`.
-	pub fn get<C>( &mut self, service: &str, callback: C ) -> Operation where
-		C: FnMut(Ego, &str, &'static mut *mut ())
+	pub fn get<C>( &self, service: &str, callback: C ) -> Operation<'_> where
+		C: FnMut(Ego, &str, &'static mut *mut ()) + 'static
 	{
 		let cservice = CString::new(service).expect("null character in `service`");
-		let cls = Box::into_raw( Box::new( callback ) );
- 
-		let inner = unsafe { GNUNET_IDENTITY_get( self.0, cservice.as_ptr(), Some( ffi_identity_callback::<C> ), cls as _ ) };
-		Operation ( inner )
+		let closure = closure::Once::new( callback );
+
+		let inner = unsafe { GNUNET_IDENTITY_get( self.inner, cservice.as_ptr(), Some( ffi_get_callback::<C> ), closure.cls() ) };
+		Operation { inner, closure, _handle: PhantomData }
 	}
 }
 
 impl Drop for Handle {
+
+	/// The `on_ego` closure is freed after disconnecting, when it can't be called anymore.
 	fn drop( &mut self ) {
-		
+		unsafe { GNUNET_IDENTITY_disconnect( self.inner ) };
+
+		for watcher in self.egos.borrow_mut().watchers.drain(..) {
+			let mut watcher = watcher.borrow_mut();
+			watcher.closed = true;
+			if let Some( waker ) = watcher.waker.take() {
+				waker.wake();
+			}
+		}
+	}
+}
+
+impl EgoList {
+
+	fn notify( &mut self, event: EgoEvent ) {
+		// Forget the streams that have been dropped.
+		self.watchers.retain( |w| Rc::strong_count( w ) > 1 );
+
+		for watcher in &self.watchers {
+			let mut watcher = watcher.borrow_mut();
+			watcher.events.push_back( event.clone() );
+			if let Some( waker ) = watcher.waker.take() {
+				waker.wake();
+			}
+		}
+	}
+
+	/// Turns a notification of the service into an event.
+	/// A NULL ego ends the initial list, a NULL name means that the ego has been removed, and a known ego with a name has been renamed.
+	fn update( &mut self, ego: *mut GNUNET_IDENTITY_Ego, name: Option<String> ) {
+		if ego == ptr::null_mut() {
+			self.complete = true;
+			self.notify( EgoEvent::InitialListComplete );
+			return
+		}
+
+		let key = ego as usize;
+		let event = match ( name, self.egos.remove( &key ) ) {
+			( Some( name ), None ) => {
+				let public_key = Ego ( ego ).get_public_key();
+				self.egos.insert( key, ( name.clone(), public_key.clone() ) );
+				EgoEvent::Added { name, public_key }
+			},
+			( Some( new_name ), Some( ( old_name, public_key ) ) ) => {
+				self.egos.insert( key, ( new_name.clone(), public_key.clone() ) );
+				EgoEvent::Renamed { old_name, new_name, public_key }
+			},
+			( None, Some( ( name, public_key ) ) ) => EgoEvent::Removed { name, public_key },
+			( None, None ) => return
+		};
+		self.notify( event );
 	}
 }
 
-impl Operation {
+impl Operation<'_> {
 
+	/// Cancels the operation, if it hasn't completed yet.
+	/// Dropping an operation without cancelling it lets it complete.
 	pub fn cancel( self ) {
-		unsafe { GNUNET_IDENTITY_cancel( self.0 ) };
+		if self.closure.is_pending() {
+			unsafe {
+				GNUNET_IDENTITY_cancel( self.inner );
+				self.closure.cancel();
+			}
+		}
+	}
+}
+
+impl<'h, T> OperationFuture<'h, T> where
+	T: 'static
+{
+
+	/// Starts the operation with a closure that completes the future.
+	fn start<S>( start: S ) -> Self where
+		S: FnOnce( Box<dyn FnOnce(T)> ) -> Operation<'h>
+	{
+		let state = Rc::new( RefCell::new( OperationState { result: None, waker: None } ) );
+		let complete_state = state.clone();
+
+		let operation = start( Box::new( move |result| {
+			let mut state = complete_state.borrow_mut();
+			state.result = Some( result );
+			if let Some( waker ) = state.waker.take() {
+				waker.wake();
+			}
+		} ) );
+
+		Self {
+			operation: Some( operation ),
+			state
+		}
+	}
+}
+
+impl<T> Drop for OperationFuture<'_, T> {
+
+	/// Cancels the operation, unless it has completed already.
+	fn drop( &mut self ) {
+		if let Some( operation ) = self.operation.take() {
+			operation.cancel();
+		}
+	}
+}
+
+impl<T> Future for OperationFuture<'_, T> {
+	type Output = T;
+
+	fn poll( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<T> {
+		let mut state = self.state.borrow_mut();
+
+		match state.result.take() {
+			Some( result ) => Poll::Ready( result ),
+			None => {
+				state.waker = Some( cx.waker().clone() );
+				Poll::Pending
+			}
+		}
+	}
+}
+
+impl fmt::Display for InvalidKeyError {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "invalid key")
+	}
+}
+
+impl error::Error for InvalidKeyError {}
+
+impl KeyType {
+
+	fn from_inner( inner: u32 ) -> Option<Self> {
+		match inner {
+			GNUNET_IDENTITY_KeyType_GNUNET_IDENTITY_TYPE_ECDSA => Some( KeyType::Ecdsa ),
+			GNUNET_IDENTITY_KeyType_GNUNET_IDENTITY_TYPE_EDDSA => Some( KeyType::Eddsa ),
+			_ => None
+		}
+	}
+
+	fn to_inner( &self ) -> GNUNET_IDENTITY_KeyType {
+		match self {
+			KeyType::Ecdsa => GNUNET_IDENTITY_KeyType_GNUNET_IDENTITY_TYPE_ECDSA,
+			KeyType::Eddsa => GNUNET_IDENTITY_KeyType_GNUNET_IDENTITY_TYPE_EDDSA
+		}
+	}
+}
+
+impl PrivateKey {
+
+	/// Reads a key that has been written with `to_bytes`.
+	pub fn from_bytes( bytes: &[u8] ) -> Result<Self, InvalidKeyError> {
+		if bytes.len() != PRIVATE_KEY_SIZE {
+			return Err( InvalidKeyError )
+		}
+
+		let type_ = u32::from_be_bytes( [bytes[0], bytes[1], bytes[2], bytes[3]] );
+		let key_type = KeyType::from_inner( type_ ).ok_or( InvalidKeyError )?;
+		let mut key = Self ( unsafe { mem::zeroed() } );
+		key.0.type_ = type_.to_be();
+
+		unsafe { match key_type {
+			KeyType::Ecdsa => key.0.__bindgen_anon_1.ecdsa_key.d.copy_from_slice( &bytes[4..] ),
+			KeyType::Eddsa => key.0.__bindgen_anon_1.eddsa_key.d.copy_from_slice( &bytes[4..] )
+		} }
+		Ok( key )
+	}
+
+	/// Creates a new random key.
+	pub fn generate( key_type: KeyType ) -> Self {
+		let mut key = Self ( unsafe { mem::zeroed() } );
+		key.0.type_ = key_type.to_inner().to_be();
+
+		unsafe { match key_type {
+			KeyType::Ecdsa => GNUNET_CRYPTO_ecdsa_key_create( &mut key.0.__bindgen_anon_1.ecdsa_key as _ ),
+			KeyType::Eddsa => GNUNET_CRYPTO_eddsa_key_create( &mut key.0.__bindgen_anon_1.eddsa_key as _ )
+		} }
+		key
+	}
+
+	/// The binary form of the key, which is its type in network byte order, followed by the key itself.
+	/// Fails if the type of the key is unknown.
+	pub fn to_bytes( &self ) -> Result<Vec<u8>, InvalidKeyError> {
+		let key_type = self.key_type().ok_or( InvalidKeyError )?;
+		let mut bytes = Vec::with_capacity( PRIVATE_KEY_SIZE );
+		bytes.extend_from_slice( &u32::from_be( self.0.type_ ).to_be_bytes() );
+
+		unsafe { match key_type {
+			KeyType::Ecdsa => bytes.extend_from_slice( &self.0.__bindgen_anon_1.ecdsa_key.d ),
+			KeyType::Eddsa => bytes.extend_from_slice( &self.0.__bindgen_anon_1.eddsa_key.d )
+		} }
+		Ok( bytes )
+	}
+
+	/// Decrypts data that has been encrypted with `PublicKey::encrypt` for this key.
+	/// Returns `None` if the data is too short to have been encrypted.
+	pub fn decrypt( &self, data: &[u8] ) -> Option<Vec<u8>> {
+		let ecdhe_size = mem::size_of::<GNUNET_CRYPTO_EcdhePublicKey>();
+		if data.len() < ecdhe_size {
+			return None
+		}
+
+		let ( ecdhe, ciphertext ) = data.split_at( ecdhe_size );
+		let mut result = vec![0u8; ciphertext.len()];
+
+		let size = unsafe { GNUNET_IDENTITY_decrypt(
+			ciphertext.as_ptr() as _,
+			ciphertext.len() as _,
+			&self.0 as _,
+			ecdhe.as_ptr() as _,
+			result.as_mut_ptr() as _
+		) };
+
+		if size < 0 {
+			None
+		}
+		else {
+			result.truncate( size as _ );
+			Some( result )
+		}
+	}
+
+	/// The EdDSA key, which is the kind of key that peers have, or `None` for other kinds of keys.
+	pub(in crate) fn eddsa_key( &self ) -> Option<&GNUNET_CRYPTO_EddsaPrivateKey> {
+		match self.key_type()? {
+			KeyType::Eddsa => Some( unsafe { &self.0.__bindgen_anon_1.eddsa_key } ),
+			KeyType::Ecdsa => None
+		}
+	}
+
+	pub fn key_type( &self ) -> Option<KeyType> {
+		KeyType::from_inner( u32::from_be( self.0.type_ ) )
+	}
+
+	pub fn public_key( &self ) -> PublicKey {
+		let mut public_key = PublicKey ( unsafe { mem::zeroed() } );
+
+		let result = unsafe { GNUNET_IDENTITY_key_get_public( &self.0 as _, &mut public_key.0 as _ ) };
+		assert!( result == GNUNET_GenericReturnValue_GNUNET_OK, "unable to derive public key" );
+		public_key
+	}
+
+	/// Signs `data` for the given purpose, which is one of the `GNUNET_SIGNATURE_PURPOSE_*` numbers.
+	/// The purpose is signed as well, so that a signature for one purpose can't be used for another.
+	pub fn sign( &self, purpose: u32, data: &[u8] ) -> Signature {
+		let block = signed_block( purpose, data );
+		let mut signature = Signature ( unsafe { mem::zeroed() } );
+
+		let result = unsafe { GNUNET_IDENTITY_sign_( &self.0 as _, block.as_ptr() as _, &mut signature.0 as _ ) };
+		assert!( result == GNUNET_GenericReturnValue_GNUNET_OK, "unable to sign data" );
+		signature
+	}
+}
+
+impl fmt::Debug for PrivateKey {
+
+	/// Doesn't show the key itself.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "PrivateKey({:?})", self.key_type())
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PrivateKey {
+
+	fn deserialize<D: serde::Deserializer<'de>>( deserializer: D ) -> Result<Self, D::Error> {
+		let string = String::deserialize( deserializer )?;
+		let cstring = CString::new(string).map_err( serde::de::Error::custom )?;
+		let mut key = Self ( unsafe { mem::zeroed() } );
+
+		let result = unsafe { GNUNET_IDENTITY_private_key_from_string( cstring.as_ptr(), &mut key.0 as _ ) };
+		if result != GNUNET_GenericReturnValue_GNUNET_OK {
+			return Err( serde::de::Error::custom( InvalidKeyError ) )
+		}
+		Ok( key )
+	}
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for PrivateKey {
+
+	fn serialize<S: serde::Serializer>( &self, serializer: S ) -> Result<S::Ok, S::Error> {
+		let string = unsafe {
+			let ptr = GNUNET_IDENTITY_private_key_to_string( &self.0 as _ );
+			let string = CStr::from_ptr( ptr ).to_string_lossy().into_owned();
+
+			GNUNET_free( ptr as _ );
+			string
+		};
+
+		serializer.serialize_str( &string )
+	}
+}
+
+impl Drop for PrivateKey {
+
+	fn drop( &mut self ) {
+		unsafe { ptr::write_bytes( &mut self.0 as *mut GNUNET_IDENTITY_PrivateKey, 0, 1 ) };
 	}
 }
 
 impl PublicKey {
 
-	pub fn to_string( &self ) -> String {
-		unsafe {
+	/// Encrypts data that only the private key of this public key can decrypt.
+	/// The result starts with an ephemeral public key, followed by the ciphertext.
+	///
+	/// The ciphertext is not authenticated, so combine it with a signature if the data needs to be tamper proof.
+	/// Returns `None` if the data can't be encrypted for this key, like when the type of the key is unknown.
+	pub fn encrypt( &self, data: &[u8] ) -> Option<Vec<u8>> {
+		let ecdhe_size = mem::size_of::<GNUNET_CRYPTO_EcdhePublicKey>();
+		let mut result = vec![0u8; ecdhe_size + data.len()];
+		let ( ecdhe, ciphertext ) = result.split_at_mut( ecdhe_size );
+
+		let size = unsafe { GNUNET_IDENTITY_encrypt(
+			data.as_ptr() as _,
+			data.len() as _,
+			&self.0 as _,
+			ecdhe.as_mut_ptr() as _,
+			ciphertext.as_mut_ptr() as _
+		) };
+		if size < 0 {
+			return None
+		}
+
+		result.truncate( ecdhe_size + size as usize );
+		Some( result )
+	}
+
+	/// Reads a key that has been written with `to_bytes`.
+	pub fn from_bytes( bytes: &[u8] ) -> Result<Self, InvalidKeyError> {
+		let mut key = Self ( unsafe { mem::zeroed() } );
+
+		let read = unsafe { GNUNET_IDENTITY_read_key_from_buffer( &mut key.0 as _, bytes.as_ptr() as _, bytes.len() as _ ) };
+		if read < 0 || read as usize != bytes.len() {
+			return Err( InvalidKeyError )
+		}
+		Ok( key )
+	}
+
+	/// The raw bytes of the key, whatever its type.
+	fn key_material( &self ) -> &[u8] {
+		let key = &self.0.__bindgen_anon_1;
+		unsafe { slice::from_raw_parts( key as *const _ as *const u8, mem::size_of_val( key ) ) }
+	}
+
+	pub fn key_type( &self ) -> Option<KeyType> {
+		KeyType::from_inner( u32::from_be( self.0.type_ ) )
+	}
+
+	/// The binary form of the key, which starts with its type.
+	/// Fails if the type of the key is unknown.
+	pub fn to_bytes( &self ) -> Result<Vec<u8>, InvalidKeyError> {
+		let length = unsafe { GNUNET_IDENTITY_key_get_length( &self.0 as _ ) };
+		if length < 0 {
+			return Err( InvalidKeyError )
+		}
+
+		let mut bytes = vec![0u8; length as _];
+		unsafe { GNUNET_IDENTITY_write_key_to_buffer( &self.0 as _, bytes.as_mut_ptr() as _, bytes.len() as _ ) };
+		Ok( bytes )
+	}
+
+	/// Checks whether `signature` has been made by the private key of this public key, for the given purpose and data.
+	pub fn verify( &self, purpose: u32, data: &[u8], signature: &Signature ) -> bool {
+		let block = signed_block( purpose, data );
+
+		let result = unsafe { GNUNET_IDENTITY_signature_verify_( purpose, block.as_ptr() as _, &signature.0 as _, &self.0 as _ ) };
+		result == GNUNET_GenericReturnValue_GNUNET_OK
+	}
+}
+
+impl fmt::Debug for PublicKey {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "PublicKey({})", self)
+	}
+}
+
+impl fmt::Display for PublicKey {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let string = unsafe {
 			let ptr = GNUNET_IDENTITY_public_key_to_string( &self.0 as _ );
-			let cstr = CStr::from_ptr( ptr );
-			let result = cstr.to_str().unwrap();
+			let string = CStr::from_ptr( ptr ).to_string_lossy().into_owned();
 
 			GNUNET_free( ptr as _ );
-			result.to_owned()
+			string
+		};
+
+		write!(f, "{}", string)
+	}
+}
+
+impl Eq for PublicKey {}
+
+impl FromStr for PublicKey {
+	type Err = InvalidKeyError;
+
+	/// Parses the format of `to_string`, which is also the one that `gnunet-identity` shows.
+	fn from_str( string: &str ) -> Result<Self, InvalidKeyError> {
+		let cstring = CString::new(string).map_err( |_| InvalidKeyError )?;
+		let mut key = Self ( unsafe { mem::zeroed() } );
+
+		let result = unsafe { GNUNET_IDENTITY_public_key_from_string( cstring.as_ptr(), &mut key.0 as _ ) };
+		if result != GNUNET_GenericReturnValue_GNUNET_OK {
+			return Err( InvalidKeyError )
 		}
+		Ok( key )
 	}
 }
 
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PublicKey {
 
+	fn deserialize<D: serde::Deserializer<'de>>( deserializer: D ) -> Result<Self, D::Error> {
+		String::deserialize( deserializer )?.parse().map_err( serde::de::Error::custom )
+	}
+}
 
-unsafe extern "C" fn ffi_create_callback<C>( cls: *mut c_void, pk: *const GNUNET_IDENTITY_PrivateKey, emsg: *const c_char ) where
-	C: FnOnce(Result<PrivateKeyHandle, MsgError>)
+#[cfg(feature = "serde")]
+impl serde::Serialize for PublicKey {
+
+	fn serialize<S: serde::Serializer>( &self, serializer: S ) -> Result<S::Ok, S::Error> {
+		serializer.collect_str( self )
+	}
+}
+
+impl PartialEq for PublicKey {
+
+	/// Keys are equal when their types and their key material are, also when the type is unknown.
+	fn eq( &self, other: &Self ) -> bool {
+		self.0.type_ == other.0.type_ && self.key_material() == other.key_material()
+	}
+}
+
+impl Signature {
+
+	/// Reads a signature that has been written with `to_bytes`.
+	pub fn from_bytes( bytes: &[u8] ) -> Result<Self, InvalidKeyError> {
+		let mut signature = Self ( unsafe { mem::zeroed() } );
+
+		let read = unsafe { GNUNET_IDENTITY_read_signature_from_buffer( &mut signature.0 as _, bytes.as_ptr() as _, bytes.len() as _ ) };
+		if read < 0 || read as usize != bytes.len() {
+			return Err( InvalidKeyError )
+		}
+		Ok( signature )
+	}
+
+	/// The binary form of the signature, which starts with the type of the key that made it.
+	pub fn to_bytes( &self ) -> Vec<u8> {
+		let length = unsafe { GNUNET_IDENTITY_signature_get_length( &self.0 as _ ) };
+		assert!( length >= 0, "unknown signature type" );
+
+		let mut bytes = vec![0u8; length as _];
+		unsafe { GNUNET_IDENTITY_write_signature_to_buffer( &self.0 as _, bytes.as_mut_ptr() as _, bytes.len() as _ ) };
+		bytes
+	}
+}
+
+impl Stream for Watch {
+	type Item = EgoEvent;
+
+	fn poll_next( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Option<EgoEvent>> {
+		let mut state = self.state.borrow_mut();
+
+		if let Some( event ) = state.events.pop_front() {
+			Poll::Ready( Some( event ) )
+		}
+		else if state.closed {
+			Poll::Ready( None )
+		}
+		else {
+			state.waker = Some( cx.waker().clone() );
+			Poll::Pending
+		}
+	}
+}
+
+
+
+/// Puts `data` behind a `GNUNET_CRYPTO_EccSignaturePurpose` header, which is what gets signed.
+/// The block is made of `u32`s so that the header is aligned.
+fn signed_block( purpose: u32, data: &[u8] ) -> Vec<u32> {
+	let header_size = mem::size_of::<GNUNET_CRYPTO_EccSignaturePurpose>();
+	let size = header_size + data.len();
+	assert!( size <= u32::MAX as usize, "data too large to sign" );
+
+	let mut block = vec![0u32; ( size + 3 ) / 4];
+	block[0] = ( size as u32 ).to_be();
+	block[1] = purpose.to_be();
+	unsafe { ptr::copy_nonoverlapping( data.as_ptr(), ( block.as_mut_ptr() as *mut u8 ).add( header_size ), data.len() ) };
+	block
+}
+
+
+
+unsafe extern "C" fn ffi_continuation<C>( cls: *mut c_void, emsg: *const c_char ) where
+	C: FnOnce(Result<(), MsgError>)
 {
-	if cls == ptr::null_mut() {
-		return
+	let closure = match closure::Once::take::<C>( cls ) {
+		Some( closure ) => closure,
+		None => return
+	};
+
+	if emsg == ptr::null() {
+		closure( Ok(()) );
+	}
+	else {
+		closure( Err( MsgError::new( emsg ) ) );
 	}
+}
 
-	let closure: Box<C> = Box::from_raw( cls as _ );
+unsafe extern "C" fn ffi_create_callback<C>( cls: *mut c_void, pk: *const GNUNET_IDENTITY_PrivateKey, emsg: *const c_char ) where
+	C: FnOnce(Result<PrivateKey, MsgError>)
+{
+	let closure = match closure::Once::take::<C>( cls ) {
+		Some( closure ) => closure,
+		None => return
+	};
 
 	if emsg == ptr::null() {
-		let private_key = PrivateKeyHandle ( pk as _ );
-		closure( Ok( private_key ) );
+		closure( Ok( PrivateKey ( *pk ) ) );
 	}
 	else {
 		let error = MsgError::new( emsg );
@@ -211,30 +880,121 @@ unsafe extern "C" fn ffi_create_callback<C>( cls: *mut c_void, pk: *const GNUNET
 	}
 }
 
-unsafe extern "C" fn ffi_identity_callback<C>(
+unsafe extern "C" fn ffi_identity_callback(
+	cls: *mut c_void,
+	_ego: *mut GNUNET_IDENTITY_Ego,
+	_ctx: *mut *mut c_void,
+	name: *const c_char
+) {
+	let callbacks: &mut EgoCallbacks = closure::Repeating::get( cls );
+	// Names that aren't valid UTF-8 have their invalid parts replaced.
+	let name = if name != ptr::null() {
+		Some( CStr::from_ptr( name ).to_string_lossy() )
+	} else { None };
+
+	callbacks.list.borrow_mut().update( _ego, name.as_ref().map( |n| n.clone().into_owned() ) );
+
+	// Removed egos don't have a name anymore.
+	if let ( Some( on_ego ), Some( name ) ) = ( &mut callbacks.on_ego, name ) {
+		if _ego != ptr::null_mut() {
+			let ego = Ego ( _ego );
+			let ctx = &mut *(_ctx as *mut *mut ());
+
+			on_ego( ego, &name, ctx );
+		}
+	}
+}
+
+unsafe extern "C" fn ffi_get_callback<C>(
 	cls: *mut c_void,
 	_ego: *mut GNUNET_IDENTITY_Ego,
 	_ctx: *mut *mut c_void,
 	name: *const c_char
 ) where C: FnMut(Ego, &str, &'static mut *mut ()) {
 
-	if _ego != ptr::null_mut() {
-		let mut closure: Box<C> = Box::from_raw( cls as _ );
-		let ego = Ego ( _ego );
-		let ctx = &mut *(_ctx as *mut *mut ());
-		let cname = CStr::from_ptr( name ).to_str().expect("invalid name");
+	let closure = closure::Once::take::<C>( cls );
 
-		closure( ego, cname, ctx );
+	if let Some( mut closure ) = closure {
+		if _ego != ptr::null_mut() {
+			let ego = Ego ( _ego );
+			let ctx = &mut *(_ctx as *mut *mut ());
+			let cname = CStr::from_ptr( name ).to_string_lossy();
+
+			closure( ego, &cname, ctx );
+		}
 	}
 }
 
 unsafe extern "C" fn ffi_lookup_callback<C>( cls: *mut c_void, _ego: *mut GNUNET_IDENTITY_Ego ) where
 	C: FnOnce( Option<Ego> )
 {
-	let closure: Box<C> = Box::from_raw( cls as _ );
-	let ego = if _ego == ptr::null_mut() {
+	let closure = match closure::Once::take::<C>( cls ) {
+		Some( closure ) => closure,
+		None => return
+	};
+	let ego = if _ego != ptr::null_mut() {
 		Some( Ego( _ego ) )
 	} else { None };
 
 	closure( ego );
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A copy of the key, with a type that isn't known.
+	fn with_unknown_type( key: &PublicKey ) -> PublicKey {
+		let mut key = key.clone();
+		key.0.type_ = 0xFFFF_u32.to_be();
+		key
+	}
+
+	#[test]
+	fn public_keys_compare_by_type_and_key() {
+		let first = PrivateKey::generate( KeyType::Eddsa ).public_key();
+		let second = PrivateKey::generate( KeyType::Eddsa ).public_key();
+
+		assert!( first == first.clone() );
+		assert!( first != second );
+		assert!( first != PrivateKey::generate( KeyType::Ecdsa ).public_key() );
+	}
+
+	#[test]
+	fn public_keys_of_unknown_type_compare_without_panicking() {
+		let key = PrivateKey::generate( KeyType::Eddsa ).public_key();
+		let unknown = with_unknown_type( &key );
+
+		assert!( unknown == unknown.clone() );
+		assert!( unknown != key );
+		assert!( unknown != with_unknown_type( &PrivateKey::generate( KeyType::Eddsa ).public_key() ) );
+	}
+
+	#[test]
+	fn keys_of_unknown_type_fail_without_panicking() {
+		let mut private_key = PrivateKey::generate( KeyType::Eddsa );
+		let public_key = with_unknown_type( &private_key.public_key() );
+		private_key.0.type_ = 0xFFFF_u32.to_be();
+
+		assert!( private_key.to_bytes().is_err() );
+		assert!( public_key.to_bytes().is_err() );
+		assert!( public_key.encrypt( b"secret" ).is_none() );
+	}
+
+	#[test]
+	fn encrypted_data_is_decrypted() {
+		let private_key = PrivateKey::generate( KeyType::Ecdsa );
+		let encrypted = private_key.public_key().encrypt( b"secret" ).unwrap();
+
+		assert_eq!( private_key.decrypt( &encrypted ), Some( b"secret".to_vec() ) );
+	}
+
+	#[test]
+	fn private_keys_round_trip() {
+		let key = PrivateKey::generate( KeyType::Eddsa );
+		let read = PrivateKey::from_bytes( &key.to_bytes().unwrap() ).unwrap();
+
+		assert_eq!( read.to_bytes().unwrap(), key.to_bytes().unwrap() );
+	}
+}
@@ -1,7 +1,10 @@
 #[cfg(feature = "cadet")]
 pub mod cadet;
+mod closure;
 pub mod common;
 pub mod configuration;
+#[cfg(feature = "core")]
+pub mod core;
 pub mod crypto;
 pub mod error;
 pub mod future;
@@ -10,7 +13,9 @@ pub mod mq;
 #[cfg(feature = "peerstore")]
 pub mod peerstore;
 pub mod program;
+pub mod runtime;
 pub mod scheduler;
+pub mod time;
 
 
 
@@ -1,70 +1,717 @@
 use gnunet_sys::*;
 
+use std::cell::RefCell;
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::mem;
 use std::os::raw::*;
+use std::pin::Pin;
+use std::ptr;
+use std::rc::Rc;
 use std::slice;
+use std::task::{Context, Poll, Waker};
 
+use crate::closure;
 
 
+
+/// A message that is ready to be sent through a message queue.
+/// An envelope that is dropped without being sent is discarded.
+pub struct Envelope {
+	inner: *mut GNUNET_MQ_Envelope,
+	options: u32,
+	notify_sent: Option<closure::Once>
+}
+
 pub struct Handle ( *mut GNUNET_MQ_Handle );
 
+/// An owned copy of a received message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+	pub type_: u16,
+	/// The content of the message, without the header.
+	pub payload: Vec<u8>
+}
+
 pub struct MessageHandle ( *const GNUNET_MessageHeader );
 
-pub struct MessageHandlerData {
-	on_message_validation: Box<dyn FnMut()>,
-	on_message: Box<dyn FnMut()>
+/// Preferences that can be combined with a [`Priority`] for how an envelope is transmitted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preference {
+	/// The message may be lost.
+	Unreliable,
+	/// Latency is more important than bandwidth.
+	LowLatency,
+	/// The message may be delayed to combine it with others.
+	CorkAllowed,
+	/// Bandwidth is more important than latency.
+	Goodput,
+	/// The message may be delivered out of order.
+	OutOfOrder
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+	Background,
+	BestEffort,
+	Urgent,
+	CriticalControl
+}
+
+/// A future that resolves once a message has been handed to the transport, or fails when the message queue is destroyed before that.
+/// Dropping it doesn't cancel sending the message, only the notification.
+pub struct SendFuture {
+	envelope: *mut GNUNET_MQ_Envelope,
+	destroy_notify: *mut GNUNET_MQ_DestroyNotificationHandle,
+	on_destroy: closure::Once,
+	on_sent: closure::Once,
+	state: Rc<RefCell<SendState>>
+}
+
+#[derive(Debug)]
+pub enum SendError {
+	/// The message queue has been destroyed before the message was sent.
+	Destroyed
+}
+
+#[derive(Default)]
+struct SendState {
+	sent: bool,
+	destroyed: bool,
+	waker: Option<Waker>
+}
+
+/// Services like CORE and CADET replace the closure of every message handler with a per-peer or per-channel context of their own.
+/// Such a context has to start with a `HandlerContext`, so that every handler can still look up its own closure by message type.
+#[repr(C)]
+pub(in crate) struct HandlerContext ( *const HandlerTable );
+
+pub(in crate) struct HandlerTable ( Vec<(u16, *mut c_void)> );
+
+struct HandlerClosures<V,M> {
+	validate: V,
+	on_message: M
+}
+
+pub struct MessageHandler {
+	inner: GNUNET_MQ_MessageHandler,
+	_closures: closure::Repeating
+}
+
+/// A set of message handlers, that can be given to a service.
+pub struct MessageHandlers {
+	handlers: Vec<MessageHandler>,
+	table: Box<HandlerTable>,
+	context: Box<HandlerContext>
+}
+
+/// A message payload that can be decoded from the bytes following the message header.
+pub trait Decode: Sized {
+
+	/// Returns `None` if the payload is malformed.
+	fn decode( payload: &[u8] ) -> Option<Self>;
+}
+
+/// A message payload that can be encoded into the bytes following the message header.
+pub trait Encode {
+
+	fn encode( &self ) -> Vec<u8>;
+}
+
+
+
+impl Envelope {
+
+	/// Creates an envelope for a message of the given type, with the encoded `value` as its payload.
+	pub fn encode<T>( type_: u16, value: &T ) -> Self where
+		T: Encode
+	{
+		Self::new( type_, &value.encode() )
+	}
+
+	/// Creates an envelope for a message of the given type, with `payload` following the message header.
+	pub fn new( type_: u16, payload: &[u8] ) -> Self {
+		let size = mem::size_of::<GNUNET_MessageHeader>() + payload.len();
+		assert!( size <= u16::MAX as usize, "payload too large for a single message" );
+
+		let mut header: *mut GNUNET_MessageHeader = ptr::null_mut();
+		let inner = unsafe { GNUNET_MQ_msg_( &mut header as _, size as _, type_ ) };
+		assert!( inner != ptr::null_mut(), "unable to allocate envelope" );
+
+		unsafe { ptr::copy_nonoverlapping( payload.as_ptr(), header.offset(1) as *mut u8, payload.len() ) };
+
+		Self {
+			inner,
+			options: 0,
+			notify_sent: None
+		}
+	}
+
+	/// Sets the closure that will be called once the message has been handed to the transport.
+	/// If the message queue gets destroyed before that, it will never be called.
+	pub fn notify_sent<F>( &mut self, on_sent: F ) where
+		F: FnOnce() + 'static
+	{
+		let on_sent = closure::Once::new( on_sent );
+
+		unsafe {
+			GNUNET_MQ_notify_sent( self.inner, Some( ffi_notify_sent::<F> ), on_sent.cls() );
+
+			// The previous closure has been replaced, so it won't be called anymore.
+			if let Some( previous ) = self.notify_sent.replace( on_sent ) {
+				previous.cancel();
+			}
+		}
+	}
+
+	pub fn set_preference( &mut self, preference: Preference ) {
+		self.options |= match preference {
+			Preference::Unreliable => GNUNET_MQ_PriorityPreferences_GNUNET_MQ_PREF_UNRELIABLE,
+			Preference::LowLatency => GNUNET_MQ_PriorityPreferences_GNUNET_MQ_PREF_LOW_LATENCY,
+			Preference::CorkAllowed => GNUNET_MQ_PriorityPreferences_GNUNET_MQ_PREF_CORK_ALLOWED,
+			Preference::Goodput => GNUNET_MQ_PriorityPreferences_GNUNET_MQ_PREF_GOODPUT,
+			Preference::OutOfOrder => GNUNET_MQ_PriorityPreferences_GNUNET_MQ_PREF_OUT_OF_ORDER
+		} as u32;
+
+		unsafe { GNUNET_MQ_env_set_options( self.inner, self.options as _ ) };
+	}
+
+	pub fn set_priority( &mut self, priority: Priority ) {
+		let cpriority = match priority {
+			Priority::Background => GNUNET_MQ_PriorityPreferences_GNUNET_MQ_PRIO_BACKGROUND,
+			Priority::BestEffort => GNUNET_MQ_PriorityPreferences_GNUNET_MQ_PRIO_BEST_EFFORT,
+			Priority::Urgent => GNUNET_MQ_PriorityPreferences_GNUNET_MQ_PRIO_URGENT,
+			Priority::CriticalControl => GNUNET_MQ_PriorityPreferences_GNUNET_MQ_PRIO_CRITICAL_CONTROL
+		} as u32;
+		let mask = GNUNET_MQ_PriorityPreferences_GNUNET_MQ_PRIORITY_MASK as u32;
+		self.options = ( self.options & !mask ) | cpriority;
+
+		unsafe { GNUNET_MQ_env_set_options( self.inner, self.options as _ ) };
+	}
+
+	/// Copies the message out of the envelope, so that tests can check what would have been sent.
+	#[cfg(test)]
+	pub(in crate) fn to_message( &self ) -> Message {
+		unsafe {
+			let header = GNUNET_MQ_env_get_msg( self.inner );
+			let size = u16::from_be( (*header).size ) as usize;
+
+			Message {
+				type_: u16::from_be( (*header).type_ ),
+				payload: slice::from_raw_parts( header.offset(1) as *const u8, size - mem::size_of::<GNUNET_MessageHeader>() ).to_vec()
+			}
+		}
+	}
+
+	/// Copies the message and options of the envelope into a new one, without its `notify_sent` closure.
+	fn copy( &self ) -> Self {
+		let inner = unsafe { GNUNET_MQ_msg_copy( GNUNET_MQ_env_get_msg( self.inner ) ) };
+		assert!( inner != ptr::null_mut(), "unable to allocate envelope" );
+
+		if self.options != 0 {
+			unsafe { GNUNET_MQ_env_set_options( inner, self.options as _ ) };
+		}
+
+		Self {
+			inner,
+			options: self.options,
+			notify_sent: None
+		}
+	}
+
+	/// Gives up ownership of the envelope, as the message queue takes it over when it is sent.
+	fn into_inner( mut self ) -> *mut GNUNET_MQ_Envelope {
+		let inner = self.inner;
+		self.inner = ptr::null_mut();
+		self.notify_sent = None;
+		inner
+	}
 }
 
-pub struct MessageHandler ( pub(in crate) GNUNET_MQ_MessageHandler );
+impl Drop for Envelope {
 
+	fn drop( &mut self ) {
+		if self.inner != ptr::null_mut() {
+			unsafe {
+				GNUNET_MQ_discard( self.inner );
 
+				if let Some( on_sent ) = self.notify_sent.take() {
+					on_sent.cancel();
+				}
+			}
+		}
+	}
+}
 
 impl Handle {
 
 	pub fn from_inner( inner: *mut GNUNET_MQ_Handle ) -> Self {
 		Self ( inner )
 	}
+
+	/// Whether no messages are waiting in the queue to be sent.
+	pub fn is_empty( &self ) -> bool {
+		self.len() == 0
+	}
+
+	/// The number of messages that are waiting in the queue to be sent.
+	pub fn len( &self ) -> usize {
+		unsafe { GNUNET_MQ_get_length( self.0 ) as _ }
+	}
+
+	/// Queues the envelope for sending.
+	pub fn send( &self, envelope: Envelope ) {
+		unsafe { GNUNET_MQ_send( self.0, envelope.into_inner() ) };
+	}
+
+	/// Sends the envelope, and returns a future that resolves once the message has been handed to the transport.
+	/// Any `notify_sent` closure of the envelope is replaced.
+	pub fn send_async( &self, mut envelope: Envelope ) -> SendFuture {
+		let state = Rc::new( RefCell::new( SendState::default() ) );
+
+		let sent_state = state.clone();
+		envelope.notify_sent( move || {
+			let mut state = sent_state.borrow_mut();
+			state.sent = true;
+			if let Some( waker ) = state.waker.take() {
+				waker.wake();
+			}
+		} );
+		let on_sent = envelope.notify_sent.take().unwrap();
+
+		let destroyed_state = state.clone();
+		let on_destroy: Box<dyn FnOnce()> = Box::new( move || {
+			let mut state = destroyed_state.borrow_mut();
+			state.destroyed = true;
+			if let Some( waker ) = state.waker.take() {
+				waker.wake();
+			}
+		} );
+		let on_destroy = closure::Once::new( on_destroy );
+		let destroy_notify = unsafe { GNUNET_MQ_destroy_notify( self.0, Some( ffi_notify_sent::<Box<dyn FnOnce()>> ), on_destroy.cls() ) };
+
+		let inner = envelope.into_inner();
+		unsafe { GNUNET_MQ_send( self.0, inner ) };
+
+		SendFuture {
+			envelope: inner,
+			destroy_notify,
+			on_destroy,
+			on_sent,
+			state
+		}
+	}
+
+	/// Queues a copy of the envelope for sending, so that the envelope can be sent again.
+	/// The copy has the same preferences and priority, but no `notify_sent` closure.
+	pub fn send_copy( &self, envelope: &Envelope ) {
+		self.send( envelope.copy() );
+	}
+}
+
+impl Drop for SendFuture {
+
+	fn drop( &mut self ) {
+		unsafe {
+			if self.on_sent.is_pending() {
+				// Until it has been sent, the envelope is still alive, unless the queue has been destroyed.
+				if !self.state.borrow().destroyed {
+					GNUNET_MQ_notify_sent( self.envelope, None, ptr::null_mut() );
+				}
+				self.on_sent.cancel();
+			}
+
+			if self.on_destroy.is_pending() {
+				GNUNET_MQ_destroy_notify_cancel( self.destroy_notify );
+				self.on_destroy.cancel();
+			}
+		}
+	}
+}
+
+impl Future for SendFuture {
+	type Output = Result<(), SendError>;
+
+	fn poll( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Result<(), SendError>> {
+		let mut state = self.state.borrow_mut();
+
+		if state.sent {
+			Poll::Ready( Ok(()) )
+		}
+		else if state.destroyed {
+			Poll::Ready( Err( SendError::Destroyed ) )
+		}
+		else {
+			state.waker = Some( cx.waker().clone() );
+			Poll::Pending
+		}
+	}
+}
+
+impl fmt::Display for SendError {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			SendError::Destroyed => write!(f, "message queue destroyed")
+		}
+	}
+}
+
+impl error::Error for SendError {}
+
+impl HandlerContext {
+
+	unsafe fn table<'a>( cls: *mut c_void ) -> &'a HandlerTable {
+		&*(*(cls as *const HandlerContext)).0
+	}
+}
+
+impl HandlerTable {
+
+	fn lookup( &self, type_: u16 ) -> Option<*mut c_void> {
+		self.0.iter()
+			.find( |( t, _ )| *t == type_ )
+			.map( |( _, cls )| *cls )
+	}
+}
+
+impl Message {
+
+	/// Decodes the payload, or returns `None` if it is malformed.
+	pub fn decode<T: Decode>( &self ) -> Option<T> {
+		T::decode( &self.payload )
+	}
 }
 
 impl MessageHandle {
 
-	pub fn size( &self ) -> u16 { unsafe { (*self.0).size } }
+	pub fn size( &self ) -> u16 { unsafe { u16::from_be( (*self.0).size ) } }
 
-	pub fn type_( &self ) -> u16 { unsafe { (*self.0).type_ } }
+	pub fn type_( &self ) -> u16 { unsafe { u16::from_be( (*self.0).type_ ) } }
 
 	pub fn content( &self ) -> &[u8] {
 		unsafe {
 			let data_ptr = self.0.offset(1);
-			slice::from_raw_parts( data_ptr as *const u8, self.size() as usize )
+			slice::from_raw_parts( data_ptr as *const u8, self.size() as usize - mem::size_of::<GNUNET_MessageHeader>() )
+		}
+	}
+
+	/// Copies the message, so that it can be kept after the handler returns.
+	pub fn to_owned( &self ) -> Message {
+		Message {
+			type_: self.type_(),
+			payload: self.content().to_vec()
 		}
 	}
 }
 
 impl MessageHandler {
 
+	/// Creates a handler for messages of which the size always is `expected_size`, including the header.
 	pub fn new_static_sized<M>( type_: u16, on_message: M, expected_size: u16 ) -> Self where
-		M: FnMut(MessageHandle)
+		M: FnMut(MessageHandle) + 'static
+	{
+		let closures = closure::Repeating::new( HandlerClosures {
+			validate: (),
+			on_message
+		} );
+
+		Self {
+			inner: GNUNET_MQ_MessageHandler {
+				mv: None,
+				cb: Some( ffi_message_handler::<(),M> ),
+				cls: closures.cls(),
+				type_,
+				expected_size
+			},
+			_closures: closures
+		}
+	}
+
+	/// Creates a handler for messages which payload is decoded into `T`.
+	/// Messages that fail to decode are considered invalid.
+	pub fn new_typed<T,M>( type_: u16, mut on_message: M ) -> Self where
+		T: Decode + 'static,
+		M: FnMut(T) + 'static
 	{
-		let cls = Box::into_raw( Box::new(
+		// The message queue calls the handler right after validating the message, so the value that the validator decoded is handed over.
+		let decoded = Rc::new( RefCell::new( None ) );
+		let decoded2 = decoded.clone();
+
+		Self::new_var_sized( type_,
+			move |message| {
+				let value = T::decode( message.content() );
+				let valid = value.is_some();
+				*decoded.borrow_mut() = value;
+				valid
+			},
+			move |_| {
+				let value = decoded2.borrow_mut().take();
+				if let Some( value ) = value {
+					on_message( value );
+				}
+			}
+		)
+	}
+
+	/// Creates a handler for messages of varying size.
+	/// Every message is given to `validate` first, and when it returns `false`, the message is considered invalid and `on_message` will not be called for it.
+	pub fn new_var_sized<V,M>( type_: u16, validate: V, on_message: M ) -> Self where
+		V: FnMut(&MessageHandle) -> bool + 'static,
+		M: FnMut(MessageHandle) + 'static
+	{
+		let closures = closure::Repeating::new( HandlerClosures {
+			validate,
 			on_message
-		));
+		} );
+
+		Self {
+			inner: GNUNET_MQ_MessageHandler {
+				mv: Some( ffi_message_validator::<V,M> ),
+				cb: Some( ffi_message_handler::<V,M> ),
+				cls: closures.cls(),
+				type_,
+				expected_size: mem::size_of::<GNUNET_MessageHeader>() as _
+			},
+			_closures: closures
+		}
+	}
+
+	pub fn type_( &self ) -> u16 {
+		self.inner.type_
+	}
+}
+
+impl MessageHandlers {
+
+	pub fn new() -> Self {
+		let table = Box::new( HandlerTable ( Vec::new() ) );
+		let context = Box::new( HandlerContext ( &*table as _ ) );
+
+		Self {
+			handlers: Vec::new(),
+			table,
+			context
+		}
+	}
+
+	/// Adds a handler.
+	///
+	/// # Panics
+	/// If there already is a handler for the same message type.
+	pub fn add( mut self, handler: MessageHandler ) -> Self {
+		assert!( self.handlers.iter().all( |h| h.type_() != handler.type_() ), "duplicate handler for message type {}", handler.type_() );
+
+		self.table.0.push( ( handler.inner.type_, handler.inner.cls ) );
+		self.handlers.push( handler );
+		self
+	}
+
+	/// Returns the NULL-terminated array of handlers that the C API expects.
+	/// The closure of every handler is the context of this set.
+	pub(in crate) fn to_array( &self ) -> Vec<GNUNET_MQ_MessageHandler> {
+		let mut array: Vec<GNUNET_MQ_MessageHandler> = self.handlers.iter().map( |h| GNUNET_MQ_MessageHandler {
+			cls: self.context_ptr() as _,
+			..h.inner
+		} ).collect();
+		array.push( unsafe { mem::zeroed() } );
+		array
+	}
+
+	/// Returns a new context for services that replace the handler closures.
+	pub(in crate) fn new_context( &self ) -> HandlerContext {
+		HandlerContext ( &*self.table as _ )
+	}
+
+	pub(in crate) fn context_ptr( &self ) -> *mut HandlerContext {
+		&*self.context as *const HandlerContext as _
+	}
+}
+
+impl Decode for Vec<u8> {
+
+	fn decode( payload: &[u8] ) -> Option<Self> {
+		Some( payload.to_vec() )
+	}
+}
+
+impl Decode for String {
+
+	fn decode( payload: &[u8] ) -> Option<Self> {
+		String::from_utf8( payload.to_vec() ).ok()
+	}
+}
+
+impl Encode for [u8] {
+
+	fn encode( &self ) -> Vec<u8> {
+		self.to_vec()
+	}
+}
+
+impl Encode for Vec<u8> {
+
+	fn encode( &self ) -> Vec<u8> {
+		self.clone()
+	}
+}
+
+impl Encode for str {
+
+	fn encode( &self ) -> Vec<u8> {
+		self.as_bytes().to_vec()
+	}
+}
+
+impl Encode for String {
 
-		Self ( GNUNET_MQ_MessageHandler {
-			mv: None,
-			cb: Some( ffi_message_handler::<M> ),
-			cls: cls as _,
-			type_,
-			expected_size
-		} )
+	fn encode( &self ) -> Vec<u8> {
+		self.as_bytes().to_vec()
 	}
 }
 
 
 
-unsafe extern "C" fn ffi_message_handler<M>( cls: *mut c_void, msg: *const GNUNET_MessageHeader ) where
+unsafe extern "C" fn ffi_notify_sent<F>( cls: *mut c_void ) where
+	F: FnOnce()
+{
+	if let Some( on_sent ) = closure::Once::take::<F>( cls ) {
+		on_sent();
+	}
+}
+
+unsafe extern "C" fn ffi_message_handler<V,M>( cls: *mut c_void, msg: *const GNUNET_MessageHeader ) where
 	M: FnMut(MessageHandle)
 {
-	let mut on_message: Box<M> = Box::from_raw( cls as _ );
 	let message = MessageHandle ( msg );
 
-	on_message( message );
-}
\ No newline at end of file
+	// Panicking here would abort the process, so a message that has no handler is silently dropped.
+	if let Some( handler_cls ) = HandlerContext::table( cls ).lookup( message.type_() ) {
+		let closures: &mut HandlerClosures<V,M> = closure::Repeating::get( handler_cls );
+		(closures.on_message)( message );
+	}
+}
+
+unsafe extern "C" fn ffi_message_validator<V,M>( cls: *mut c_void, msg: *const GNUNET_MessageHeader ) -> c_int where
+	V: FnMut(&MessageHandle) -> bool
+{
+	let message = MessageHandle ( msg );
+
+	// A message without a handler is considered valid, and then ignored by `ffi_message_handler`.
+	let handler_cls = match HandlerContext::table( cls ).lookup( message.type_() ) {
+		None => return GNUNET_GenericReturnValue_GNUNET_OK,
+		Some( c ) => c
+	};
+	let closures: &mut HandlerClosures<V,M> = closure::Repeating::get( handler_cls );
+
+	if (closures.validate)( &message ) { GNUNET_GenericReturnValue_GNUNET_OK } else { GNUNET_GenericReturnValue_GNUNET_SYSERR }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{cell::Cell, time::Duration};
+
+	use crate::{configuration::Configuration, runtime::Runtime, scheduler};
+
+	/// A message queue that records every message that is sent through it.
+	struct TestQueue {
+		inner: *mut GNUNET_MQ_Handle,
+		sent: Box<RefCell<Vec<Message>>>
+	}
+
+	impl TestQueue {
+
+		fn new() -> Self {
+			let sent = Box::new( RefCell::new( Vec::new() ) );
+			let inner = unsafe { GNUNET_MQ_queue_for_callbacks(
+				Some( ffi_test_send ),
+				Some( ffi_test_destroy ),
+				Some( ffi_test_cancel ),
+				&*sent as *const _ as _,
+				ptr::null(),
+				None,
+				ptr::null_mut()
+			) };
+			assert!( inner != ptr::null_mut() );
+
+			Self { inner, sent }
+		}
+
+		fn handle( &self ) -> Handle {
+			Handle::from_inner( self.inner )
+		}
+
+		fn sent( &self ) -> Vec<Message> {
+			self.sent.borrow().clone()
+		}
+	}
+
+	impl Drop for TestQueue {
+
+		fn drop( &mut self ) {
+			unsafe { GNUNET_MQ_destroy( self.inner ) };
+		}
+	}
+
+	unsafe extern "C" fn ffi_test_send( mq: *mut GNUNET_MQ_Handle, msg: *const GNUNET_MessageHeader, impl_state: *mut c_void ) {
+		let sent = &*( impl_state as *const RefCell<Vec<Message>> );
+		sent.borrow_mut().push( MessageHandle ( msg ).to_owned() );
+		GNUNET_MQ_impl_send_continue( mq );
+	}
+
+	unsafe extern "C" fn ffi_test_destroy( _mq: *mut GNUNET_MQ_Handle, _impl_state: *mut c_void ) {}
+
+	unsafe extern "C" fn ffi_test_cancel( _mq: *mut GNUNET_MQ_Handle, _impl_state: *mut c_void ) {}
+
+	fn runtime() -> Runtime {
+		Runtime::new( Configuration::new() ).expect("unable to start runtime")
+	}
+
+	#[test]
+	fn copies_outlive_the_original_envelope() {
+		let sent = runtime().block_on_local( || async {
+			let queue = TestQueue::new();
+			let notified = Rc::new( Cell::new( 0 ) );
+
+			let mut envelope = Envelope::new( 1, b"copy" );
+			envelope.set_priority( Priority::Urgent );
+			let notified2 = notified.clone();
+			envelope.notify_sent( move || notified2.set( notified2.get() + 1 ) );
+
+			queue.handle().send_copy( &envelope );
+			drop( envelope );
+			scheduler::delay( Duration::from_millis( 1 ) ).await;
+
+			// Only the original envelope would have notified.
+			assert_eq!( notified.get(), 0 );
+			queue.sent()
+		} );
+		assert_eq!( sent, vec![ Message { type_: 1, payload: b"copy".to_vec() } ] );
+	}
+
+	#[test]
+	fn envelopes_can_be_sent_after_a_copy() {
+		let ( sent, notified ) = runtime().block_on_local( || async {
+			let queue = TestQueue::new();
+			let notified = Rc::new( Cell::new( 0 ) );
+
+			let mut envelope = Envelope::new( 2, b"original" );
+			let notified2 = notified.clone();
+			envelope.notify_sent( move || notified2.set( notified2.get() + 1 ) );
+
+			queue.handle().send_copy( &envelope );
+			scheduler::delay( Duration::from_millis( 1 ) ).await;
+			queue.handle().send( envelope );
+			scheduler::delay( Duration::from_millis( 1 ) ).await;
+
+			( queue.sent(), notified.get() )
+		} );
+		assert_eq!( sent.len(), 2 );
+		assert!( sent.iter().all( |message| message.payload == b"original" ) );
+		assert_eq!( notified, 1 );
+	}
+}
@@ -1,13 +1,32 @@
-use crate::configuration;
+mod hello;
+mod value;
+
+pub use hello::*;
+pub use value::*;
+
+use crate::closure;
+use crate::configuration::ConfigurationRef;
 use crate::crypto::PeerIdentity;
+use crate::time::Absolute;
 
 use std::{
+	borrow::Cow,
+	cell::RefCell,
+	collections::VecDeque,
+	error,
 	ffi::{CStr, CString},
 	fmt,
+	marker::PhantomData,
+	pin::Pin,
 	ptr,
-	os::raw::*
+	os::raw::*,
+	rc::Rc,
+	slice,
+	task::{Context, Poll, Waker}
 };
 
+use futures::Stream;
+
 use gnunet_sys::*;
 
 
@@ -16,24 +35,76 @@ pub struct Handle {
 	inner: *mut GNUNET_PEERSTORE_Handle
 }
 
+/// A handle to an iteration, with which it can be cancelled.
+/// It can't outlive the handle that the iteration has been started on.
+pub struct IterateContext<'h> {
+	inner: *mut GNUNET_PEERSTORE_IterateContext,
+	closure: closure::Once,
+	_handle: PhantomData<&'h Handle>
+}
+
+#[derive(Clone, Debug)]
 pub struct IterateError {
-	msg: *const c_char
+	message: String
+}
+
+/// An owned copy of a record, that can be kept after the callback it was given to returns.
+/// The subsystem and key are converted lossily when they aren't valid UTF-8, and `key_bytes` has the key as it has been stored.
+#[derive(Clone)]
+pub struct OwnedRecord {
+	pub subsystem: String,
+	pub peer: PeerIdentity,
+	pub key: String,
+	pub key_bytes: Vec<u8>,
+	pub value: Vec<u8>,
+	pub expiry: Absolute
 }
 
 pub struct PeerIterator {
 	ps: *mut GNUNET_PEERSTORE_Handle
 }
 
+/// A record that is only valid during the callback that it is given to.
 pub struct Record {
 	inner: *const GNUNET_PEERSTORE_Record
 }
 
+/// A stream of the records of an iteration, that ends when the iteration does.
+/// The iteration is cancelled when dropped.
+pub struct Records<'h> {
+	context: IterateContext<'h>,
+	state: Rc<RefCell<RecordsState>>
+}
+
+#[derive(Default)]
+struct RecordsState {
+	records: VecDeque<Result<OwnedRecord, IterateError>>,
+	finished: bool,
+	waker: Option<Waker>
+}
+
 pub struct StoreContext {
-	inner: *mut GNUNET_PEERSTORE_StoreContext
+	inner: *mut GNUNET_PEERSTORE_StoreContext,
+	closure: closure::Once
 }
 
 pub type StoreOption = GNUNET_PEERSTORE_StoreOption;
 
+/// A stream of the records that are stored for a peer under a key, from now on.
+/// The watch is cancelled when dropped, which needs to happen before the handle that it has been started on disconnects.
+pub struct Watch<'h> {
+	inner: *mut GNUNET_PEERSTORE_WatchContext,
+	state: Rc<RefCell<WatchState>>,
+	_closure: closure::Repeating,
+	_handle: PhantomData<&'h Handle>
+}
+
+#[derive(Default)]
+struct WatchState {
+	records: VecDeque<OwnedRecord>,
+	waker: Option<Waker>
+}
+
 pub const STORE_OPTION_MULTIPLE: StoreOption = 0;
 pub const STORE_OPTION_REPLACE: StoreOption = 1;
 
@@ -42,8 +113,8 @@ pub const STORE_OPTION_REPLACE: StoreOption = 1;
 impl Handle {
 
 	/// Connects to the peerstore service and returns this handle.
-	pub fn connect( config: &configuration::Handle ) -> Self {
-		let inner = unsafe { GNUNET_PEERSTORE_connect( config.inner ) };
+	pub fn connect( config: &ConfigurationRef ) -> Self {
+		let inner = unsafe { GNUNET_PEERSTORE_connect( config.as_ptr() ) };
 		assert!( inner != ptr::null_mut(), "unable to connect peerstore" );
 
 		Self {
@@ -55,82 +126,308 @@ impl Handle {
 		unsafe { GNUNET_PEERSTORE_disconnect( self.inner, if sync_first {1} else {0} ) };
 	}
 
-	pub fn iterate<C>( &self, subsystem: &str, key: Option<&str>, on_peer: C ) where
-		C: FnMut(Result<Record, IterateError>)
+	/// Iterates over the records of the subsystem, optionally only those of the given peer and/or key.
+	/// `on_record` is called with every record, and finally with `None`, or with an error when the iteration failed.
+	pub fn iterate<C>( &self, subsystem: &str, peer: Option<&PeerIdentity>, key: Option<&str>, on_record: C ) -> IterateContext<'_> where
+		C: FnMut(Option<Result<Record, IterateError>>) + 'static
 	{
 		let csubsystem = CString::new(subsystem).expect("null character in subsystem");
-		let ckey = match key {
-			Some(p) => p.as_ptr() as *const c_char,
-			None => ptr::null_mut()
+		let ckey = key.map( |k| CString::new(k).expect("null character in key") );
+		let cpeer = match peer {
+			Some( p ) => &p.0 as *const _,
+			None => ptr::null()
 		};
-		
-		let callback_data = Box::into_raw( Box::new( on_peer ) );
 
-		unsafe { GNUNET_PEERSTORE_iterate( self.inner, csubsystem.as_ptr(), ptr::null(), ckey, Some(ffi_iterate_callback::<C>), callback_data as _ ) };
+		// The closure is released by the last call, which signals the end of the iteration.
+		let closure = closure::Once::new( on_record );
+
+		let inner = unsafe { GNUNET_PEERSTORE_iterate(
+			self.inner,
+			csubsystem.as_ptr(),
+			cpeer,
+			ckey.as_ref().map_or( ptr::null(), |k| k.as_ptr() ),
+			Some( ffi_iterate_callback::<C> ),
+			closure.cls()
+		) };
+		assert!( inner != ptr::null_mut(), "unable to iterate peerstore" );
+
+		IterateContext {
+			inner,
+			closure,
+			_handle: PhantomData
+		}
 	}
 
-	pub fn store<H>( &self, subsystem: &str, peer: &PeerIdentity, key: &str, value: Vec<u8>, expiry: u64, options: StoreOption, on_complete: H ) -> StoreContext where
-		H: FnOnce(bool)
+	/// Like `iterate`, but returns the records as a stream.
+	pub fn iterate_async( &self, subsystem: &str, peer: Option<&PeerIdentity>, key: Option<&str> ) -> Records<'_> {
+		let state = Rc::new( RefCell::new( RecordsState::default() ) );
+		let state2 = state.clone();
+
+		let context = self.iterate( subsystem, peer, key, move |result| {
+			let mut state = state2.borrow_mut();
+
+			match result {
+				Some( Ok( record ) ) => state.records.push_back( Ok( record.to_owned() ) ),
+				Some( Err( e ) ) => {
+					state.finished = true;
+					state.records.push_back( Err( e ) );
+				},
+				None => state.finished = true
+			}
+
+			if let Some( waker ) = state.waker.take() {
+				waker.wake();
+			}
+		} );
+
+		Records {
+			context,
+			state
+		}
+	}
+
+	pub fn store<H>( &self, subsystem: &str, peer: &PeerIdentity, key: &str, value: Vec<u8>, expiry: Absolute, options: StoreOption, on_complete: H ) -> StoreContext where
+		H: FnOnce(bool) + 'static
 	{
 		let csubsystem = CString::new(subsystem).expect("null character in subsystem");
 		let ckey = CString::new(key).expect("null character in key");
 
-		let expiry_struct = GNUNET_TIME_Absolute {
-			abs_value_us: expiry
-		};
-
-		let cls = Box::into_raw( Box::new( on_complete ) );
+		let closure = closure::Once::new( on_complete );
 
-		let store_ctx_inner = unsafe { GNUNET_PEERSTORE_store( self.inner, csubsystem.as_ptr(), &peer.inner, ckey.as_ptr(), value.as_ptr() as _, value.len() as _, expiry_struct, options, Some( ffi_on_complete::<H> ), cls as _ ) };
+		let store_ctx_inner = unsafe { GNUNET_PEERSTORE_store( self.inner, csubsystem.as_ptr(), &peer.0, ckey.as_ptr(), value.as_ptr() as _, value.len() as _, expiry.to_inner(), options, Some( ffi_on_complete::<H> ), closure.cls() ) };
 
 		StoreContext {
-			inner: store_ctx_inner
+			inner: store_ctx_inner,
+			closure
 		}
 	}
 
 	// pub async fn store( ... )
+
+	/// Watches for records that get stored for the given peer under the given key.
+	pub fn watch( &self, subsystem: &str, peer: &PeerIdentity, key: &str ) -> Watch<'_> {
+		let csubsystem = CString::new(subsystem).expect("null character in subsystem");
+		let ckey = CString::new(key).expect("null character in key");
+
+		let state = Rc::new( RefCell::new( WatchState::default() ) );
+		let closure = closure::Repeating::new( state.clone() );
+
+		let inner = unsafe { GNUNET_PEERSTORE_watch( self.inner, csubsystem.as_ptr(), &peer.0, ckey.as_ptr(), Some( ffi_watch_callback ), closure.cls() ) };
+		assert!( inner != ptr::null_mut(), "unable to watch peerstore" );
+
+		Watch {
+			inner,
+			state,
+			_closure: closure,
+			_handle: PhantomData
+		}
+	}
+}
+
+impl IterateContext<'_> {
+
+	/// Cancels the iteration, if it hasn't finished yet.
+	/// The callback won't be called anymore after this.
+	pub fn cancel( &mut self ) {
+		if self.closure.is_pending() {
+			unsafe {
+				GNUNET_PEERSTORE_iterate_cancel( self.inner );
+				self.closure.cancel();
+			}
+		}
+	}
 }
 
 impl fmt::Display for IterateError {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		let cstr = unsafe { CStr::from_ptr( self.msg ) };
-		write!(f, "{}", cstr.to_string_lossy().as_ref() )
+		write!(f, "{}", self.message)
 	}
 }
 
+impl error::Error for IterateError {}
+
 impl Record {
 
+	/// The time after which the record gets removed.
+	pub fn expiry( &self ) -> Absolute {
+		Absolute::from_inner( unsafe { (*self.inner).expiry } )
+	}
+
+	/// The key, of which invalid UTF-8 is replaced by `U+FFFD`.
+	/// Use `key_bytes` to tell such keys apart.
+	pub fn key( &self ) -> Cow<'_, str> {
+		String::from_utf8_lossy( self.key_bytes() )
+	}
+
+	/// The key as it has been stored.
+	pub fn key_bytes( &self ) -> &[u8] {
+		unsafe { CStr::from_ptr( (*self.inner).key ) }.to_bytes()
+	}
+
 	pub fn peer( &self ) -> PeerIdentity {
 		PeerIdentity::from_inner( unsafe { (*self.inner).peer } )
 	}
 
-	
+	/// The subsystem, of which invalid UTF-8 is replaced by `U+FFFD`.
+	/// Records are only given for the subsystem that has been asked for, so this only happens with subsystems of other clients.
+	pub fn subsystem( &self ) -> Cow<'_, str> {
+		unsafe { CStr::from_ptr( (*self.inner).sub_system ) }.to_string_lossy()
+	}
+
+	/// Copies the record, so that it can be kept after the callback returns.
+	pub fn to_owned( &self ) -> OwnedRecord {
+		OwnedRecord {
+			subsystem: self.subsystem().into_owned(),
+			peer: self.peer(),
+			key: self.key().into_owned(),
+			key_bytes: self.key_bytes().to_vec(),
+			value: self.value().to_vec(),
+			expiry: self.expiry()
+		}
+	}
+
+	pub fn value( &self ) -> &[u8] {
+		unsafe {
+			let record = &*self.inner;
+			if record.value_size == 0 {
+				&[]
+			}
+			else {
+				slice::from_raw_parts( record.value as *const u8, record.value_size as _ )
+			}
+		}
+	}
+}
+
+impl Drop for Records<'_> {
+
+	fn drop( &mut self ) {
+		self.context.cancel();
+	}
+}
+
+impl Stream for Records<'_> {
+	type Item = Result<OwnedRecord, IterateError>;
+
+	fn poll_next( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Option<Self::Item>> {
+		let mut state = self.state.borrow_mut();
+
+		if let Some( result ) = state.records.pop_front() {
+			Poll::Ready( Some( result ) )
+		}
+		else if state.finished {
+			Poll::Ready( None )
+		}
+		else {
+			state.waker = Some( cx.waker().clone() );
+			Poll::Pending
+		}
+	}
 }
 
 impl StoreContext {
 
+	/// Cancels the store request, if it hasn't completed yet.
 	pub fn cancel( &mut self ) {
-		unsafe { GNUNET_PEERSTORE_store_cancel( self.inner ) }
+		if self.closure.is_pending() {
+			unsafe {
+				GNUNET_PEERSTORE_store_cancel( self.inner );
+				self.closure.cancel();
+			}
+		}
+	}
+}
+
+impl Drop for Watch<'_> {
+
+	/// The closure is freed after cancelling, when it can't be called anymore.
+	fn drop( &mut self ) {
+		unsafe { GNUNET_PEERSTORE_watch_cancel( self.inner ) };
+	}
+}
+
+impl Stream for Watch<'_> {
+	type Item = OwnedRecord;
+
+	/// Yields the records as they get stored.
+	/// This never ends.
+	fn poll_next( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Option<OwnedRecord>> {
+		let mut state = self.state.borrow_mut();
+
+		match state.records.pop_front() {
+			Some( record ) => Poll::Ready( Some( record ) ),
+			None => {
+				state.waker = Some( cx.waker().clone() );
+				Poll::Pending
+			}
+		}
 	}
 }
 
 
 
 unsafe extern "C" fn ffi_iterate_callback<C>( data: *mut c_void, record: *const GNUNET_PEERSTORE_Record, error_msg: *const c_char ) where
-	C: FnMut(Result<Record,IterateError>)
+	C: FnMut(Option<Result<Record, IterateError>>)
 {
-	let mut callback: Box<C> = Box::from_raw( data as _ );
-	
 	if record != ptr::null() {
-		callback( Ok( Record { inner: record } ) );
+		closure::Once::borrow( data, |callback: &mut C| callback( Some( Ok( Record { inner: record } ) ) ) );
 	}
-	else if error_msg != ptr::null() {
-		callback( Err( IterateError { msg: error_msg } ) );
+	// Without a record, this is the last call.
+	else if let Some( mut callback ) = closure::Once::take::<C>( data ) {
+		if error_msg != ptr::null() {
+			let message = CStr::from_ptr( error_msg ).to_string_lossy().into_owned();
+			callback( Some( Err( IterateError { message } ) ) );
+		}
+		else {
+			callback( None );
+		}
 	}
 }
 
 unsafe extern "C" fn ffi_on_complete<H>( data: *mut c_void, success: c_int ) where H: FnOnce(bool) {
 
-	let on_complete: Box<H> = Box::from_raw( data as _ );
-	on_complete( success != 0 );
-}
\ No newline at end of file
+	if let Some( on_complete ) = closure::Once::take::<H>( data ) {
+		on_complete( success != 0 );
+	}
+}
+
+unsafe extern "C" fn ffi_watch_callback( cls: *mut c_void, record: *const GNUNET_PEERSTORE_Record, _error_msg: *const c_char ) {
+	// Errors only occur when the connection to the service is lost, after which the watch is renewed.
+	if record == ptr::null() {
+		return
+	}
+
+	let record = ( Record { inner: record } ).to_owned();
+
+	let state: &mut Rc<RefCell<WatchState>> = closure::Repeating::get( cls );
+	let mut state = state.borrow_mut();
+
+	state.records.push_back( record );
+	if let Some( waker ) = state.waker.take() {
+		waker.wake();
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::mem;
+
+	#[test]
+	fn records_with_invalid_utf8_are_kept() {
+		let subsystem = b"app\0";
+		let key = b"key-\xff\0";
+		let mut inner: GNUNET_PEERSTORE_Record = unsafe { mem::zeroed() };
+		inner.sub_system = subsystem.as_ptr() as _;
+		inner.key = key.as_ptr() as _;
+
+		let record = ( Record { inner: &inner } ).to_owned();
+
+		assert_eq!( record.subsystem, "app" );
+		assert_eq!( record.key, "key-\u{FFFD}" );
+		assert_eq!( record.key_bytes, b"key-\xff" );
+		assert!( record.value.is_empty() );
+	}
+}
@@ -0,0 +1,379 @@
+//! HELLOs, with which peers advertise the addresses that they can be reached at.
+//!
+//! Newer versions of Gnunet keep the HELLOs of all known peers in PEERSTORE.
+//! A HELLO can be exchanged as a message, or as a URI like `gnunet://hello/<peer>/<signature>/<expiration>?<scheme>=<address>&...`.
+//! Both are signed by the peer that they belong to.
+
+use gnunet_sys::*;
+
+use std::{
+	ffi::{CStr, CString},
+	mem,
+	os::raw::*,
+	pin::Pin,
+	ptr, slice,
+	task::{Context, Poll},
+	time::Duration
+};
+
+use futures::Stream;
+
+use crate::closure;
+use crate::crypto::PeerIdentity;
+use crate::identity::PrivateKey;
+use crate::mq;
+use super::{ffi_on_complete, Handle, IterateError, Records};
+
+
+
+/// The subsystem under which PEERSTORE keeps the HELLOs.
+/// Gnunet has no constant for it, PEERSTORE uses this name itself.
+pub const HELLO_SUBSYSTEM: &str = "peerstore";
+
+/// The addresses of a peer, that can be signed into a message or URI.
+pub struct Hello {
+	inner: *mut GNUNET_HELLO_Builder
+}
+
+/// A handle to a HELLO that is being added, with which it can be cancelled.
+pub struct HelloAddContext {
+	inner: *mut GNUNET_PEERSTORE_StoreHelloContext,
+	closure: closure::Once
+}
+
+/// A stream of the addresses of every peer that PEERSTORE has a HELLO of.
+/// Stored HELLOs that can't be parsed are skipped.
+pub struct KnownAddresses<'h> {
+	records: Records<'h>
+}
+
+
+
+impl Drop for Hello {
+
+	fn drop( &mut self ) {
+		unsafe { GNUNET_HELLO_builder_free( self.inner ) };
+	}
+}
+
+impl Hello {
+
+	/// Adds an address, like `tcp+192.0.2.1:2086`.
+	/// Returns `false` if the address is malformed or had been added already.
+	pub fn add_address( &mut self, address: &str ) -> bool {
+		let caddress = CString::new(address).expect("null character in address");
+
+		unsafe { GNUNET_HELLO_builder_add_address( self.inner, caddress.as_ptr() ) == GNUNET_GenericReturnValue_GNUNET_OK }
+	}
+
+	pub fn addresses( &self ) -> Vec<String> {
+		let mut addresses = Vec::<String>::new();
+		let mut peer: GNUNET_PeerIdentity = unsafe { mem::zeroed() };
+
+		unsafe { GNUNET_HELLO_builder_iterate( self.inner, &mut peer, Some( ffi_address_callback ), &mut addresses as *mut _ as _ ) };
+		addresses
+	}
+
+	/// Returns `false` if the address wasn't there.
+	pub fn del_address( &mut self, address: &str ) -> bool {
+		let caddress = CString::new(address).expect("null character in address");
+
+		unsafe { GNUNET_HELLO_builder_del_address( self.inner, caddress.as_ptr() ) == GNUNET_GenericReturnValue_GNUNET_OK }
+	}
+
+	/// Parses a HELLO message, and checks its signature.
+	/// Returns `None` if it is malformed, too large or not properly signed.
+	pub fn from_message( message: &mq::Message ) -> Option<Self> {
+		let buffer = message_buffer( message )?;
+		Self::from_header( buffer.as_ptr() as _ )
+	}
+
+	/// Parses a HELLO URI, and checks its signature.
+	/// Returns `None` if it is malformed or not properly signed.
+	pub fn from_url( url: &str ) -> Option<Self> {
+		let curl = CString::new(url).ok()?;
+
+		let inner = unsafe { GNUNET_HELLO_builder_from_url( curl.as_ptr() ) };
+		if inner == ptr::null_mut() {
+			return None
+		}
+		Some( Self { inner } )
+	}
+
+	/// Creates an empty HELLO for the given peer.
+	pub fn new( peer: &PeerIdentity ) -> Self {
+		let inner = unsafe { GNUNET_HELLO_builder_new( &peer.0 ) };
+		assert!( inner != ptr::null_mut(), "unable to create hello builder" );
+
+		Self {
+			inner
+		}
+	}
+
+	pub fn peer( &self ) -> PeerIdentity {
+		let mut peer: GNUNET_PeerIdentity = unsafe { mem::zeroed() };
+
+		unsafe { GNUNET_HELLO_builder_iterate( self.inner, &mut peer, None, ptr::null_mut() ) };
+		PeerIdentity::from_inner( peer )
+	}
+
+	/// Signs the addresses into a message, that expires after the given duration.
+	///
+	/// # Panics
+	/// When the key is not an EdDSA key, as peer keys are.
+	pub fn to_message( &self, peer_key: &PrivateKey, expiration: Duration ) -> mq::Message {
+		let key = peer_key.eddsa_key().expect("peer key is not an EdDSA key");
+		let relative = GNUNET_TIME_Relative { rel_value_us: expiration.as_micros().min( u64::MAX as u128 ) as u64 };
+
+		unsafe {
+			let envelope = GNUNET_HELLO_builder_to_env( self.inner, key, relative );
+			assert!( envelope != ptr::null_mut(), "unable to create hello message" );
+
+			let header = GNUNET_MQ_env_get_msg( envelope );
+			let size = u16::from_be( (*header).size ) as usize;
+			let message = mq::Message {
+				type_: u16::from_be( (*header).type_ ),
+				payload: slice::from_raw_parts( header.offset(1) as *const u8, size - mem::size_of::<GNUNET_MessageHeader>() ).to_vec()
+			};
+
+			GNUNET_MQ_discard( envelope );
+			message
+		}
+	}
+
+	/// Signs the addresses into a URI.
+	///
+	/// # Panics
+	/// When the key is not an EdDSA key, as peer keys are.
+	pub fn to_url( &self, peer_key: &PrivateKey ) -> String {
+		let key = peer_key.eddsa_key().expect("peer key is not an EdDSA key");
+
+		unsafe {
+			let ptr = GNUNET_HELLO_builder_to_url( self.inner, key );
+			assert!( ptr != ptr::null_mut(), "unable to create hello URI" );
+
+			let url = CStr::from_ptr( ptr ).to_string_lossy().into_owned();
+			GNUNET_free( ptr as _ );
+			url
+		}
+	}
+
+	/// Parses a stored HELLO, which is the whole message, including its header.
+	fn from_stored( value: &[u8] ) -> Option<Self> {
+		if value.len() < mem::size_of::<GNUNET_MessageHeader>() {
+			return None
+		}
+
+		let mut buffer = vec![0u16; ( value.len() + 1 ) / 2];
+		unsafe { ptr::copy_nonoverlapping( value.as_ptr(), buffer.as_mut_ptr() as *mut u8, value.len() ) };
+		if u16::from_be( buffer[0] ) as usize != value.len() {
+			return None
+		}
+
+		Self::from_header( buffer.as_ptr() as _ )
+	}
+
+	fn from_header( header: *const GNUNET_MessageHeader ) -> Option<Self> {
+		let inner = unsafe { GNUNET_HELLO_builder_from_msg( header ) };
+		if inner == ptr::null_mut() {
+			return None
+		}
+		Some( Self { inner } )
+	}
+}
+
+impl Handle {
+
+	/// Adds a signed HELLO message of another peer, so that the addresses in it become known.
+	/// `on_complete` is called with whether it has been stored.
+	///
+	/// Returns `None` without calling `on_complete` if the message is too large or not a HELLO.
+	pub fn hello_add<H>( &self, message: &mq::Message, on_complete: H ) -> Option<HelloAddContext> where
+		H: FnOnce(bool) + 'static
+	{
+		let buffer = message_buffer( message )?;
+		let header = buffer.as_ptr() as *const GNUNET_MessageHeader;
+
+		// The message is kept until the HELLO has been added.
+		self.hello_add_header( header, move |success| {
+			drop( buffer );
+			on_complete( success )
+		} )
+	}
+
+	fn hello_add_header<H>( &self, header: *const GNUNET_MessageHeader, on_complete: H ) -> Option<HelloAddContext> where
+		H: FnOnce(bool) + 'static
+	{
+		let closure = closure::Once::new( on_complete );
+
+		// Gnunet doesn't start adding messages that it can't parse.
+		let inner = unsafe { GNUNET_PEERSTORE_hello_add( self.inner, header, Some( ffi_on_complete::<H> ), closure.cls() ) };
+		if inner == ptr::null_mut() {
+			unsafe { closure.cancel() };
+			return None
+		}
+
+		Some( HelloAddContext {
+			inner,
+			closure
+		} )
+	}
+
+	/// Lists the addresses that are known from the stored HELLOs, of all peers or just the given one.
+	pub fn known_addresses( &self, peer: Option<&PeerIdentity> ) -> KnownAddresses<'_> {
+		KnownAddresses {
+			records: self.iterate_async( HELLO_SUBSYSTEM, peer, Some( hello_key() ) )
+		}
+	}
+}
+
+impl HelloAddContext {
+
+	/// Cancels adding the HELLO, if it hasn't completed yet.
+	pub fn cancel( &mut self ) {
+		if self.closure.is_pending() {
+			unsafe {
+				GNUNET_PEERSTORE_hello_add_cancel( self.inner );
+				self.closure.cancel();
+			}
+		}
+	}
+}
+
+impl Stream for KnownAddresses<'_> {
+	type Item = Result<(PeerIdentity, Vec<String>), IterateError>;
+
+	fn poll_next( mut self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<Option<Self::Item>> {
+		loop {
+			match Pin::new( &mut self.records ).poll_next( cx ) {
+				Poll::Pending => return Poll::Pending,
+				Poll::Ready( None ) => return Poll::Ready( None ),
+				Poll::Ready( Some( Err( e ) ) ) => return Poll::Ready( Some( Err( e ) ) ),
+				Poll::Ready( Some( Ok( record ) ) ) => if let Some( hello ) = Hello::from_stored( &record.value ) {
+					return Poll::Ready( Some( Ok( ( hello.peer(), hello.addresses() ) ) ) )
+				}
+			}
+		}
+	}
+}
+
+
+
+unsafe extern "C" fn ffi_address_callback( cls: *mut c_void, uri: *const c_char ) {
+	let addresses = &mut *( cls as *mut Vec<String> );
+
+	addresses.push( CStr::from_ptr( uri ).to_string_lossy().into_owned() );
+}
+
+/// The key under which PEERSTORE keeps the HELLOs.
+fn hello_key() -> &'static str {
+	CStr::from_bytes_with_nul( GNUNET_PEERSTORE_HELLO_KEY ).ok().and_then( |key| key.to_str().ok() ).expect("invalid hello key")
+}
+
+/// Puts the message, with its header, into a buffer that is aligned for the header.
+/// Returns `None` if the message is too large for its size to fit in the header.
+fn message_buffer( message: &mq::Message ) -> Option<Vec<u16>> {
+	let size = mem::size_of::<GNUNET_MessageHeader>() + message.payload.len();
+	if size > u16::MAX as usize {
+		return None
+	}
+
+	let mut buffer = vec![0u16; ( size + 1 ) / 2];
+	buffer[0] = ( size as u16 ).to_be();
+	buffer[1] = message.type_.to_be();
+	unsafe { ptr::copy_nonoverlapping( message.payload.as_ptr(), buffer.as_mut_ptr().offset(2) as *mut u8, message.payload.len() ) };
+	Some( buffer )
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::identity::KeyType;
+
+	/// A new peer key, with the identity of the peer that it belongs to.
+	fn peer_key() -> ( PrivateKey, PeerIdentity ) {
+		let key = PrivateKey::generate( KeyType::Eddsa );
+		let mut peer: GNUNET_PeerIdentity = unsafe { mem::zeroed() };
+
+		unsafe { GNUNET_CRYPTO_eddsa_key_get_public( key.eddsa_key().unwrap(), &mut peer.public_key ) };
+		( key, PeerIdentity::from_inner( peer ) )
+	}
+
+	fn hello_with_addresses( peer: &PeerIdentity ) -> Hello {
+		let mut hello = Hello::new( peer );
+		assert!( hello.add_address( "tcp+192.0.2.1:2086" ) );
+		assert!( hello.add_address( "udp+192.0.2.1:2086" ) );
+		assert!( !hello.add_address( "tcp+192.0.2.1:2086" ) );
+		hello
+	}
+
+	fn sorted_addresses( hello: &Hello ) -> Vec<String> {
+		let mut addresses = hello.addresses();
+		addresses.sort();
+		addresses
+	}
+
+	#[test]
+	fn hellos_round_trip_through_urls() {
+		let ( key, peer ) = peer_key();
+		let hello = hello_with_addresses( &peer );
+
+		let url = hello.to_url( &key );
+		assert!( url.starts_with( "gnunet://hello/" ), "unexpected url {}", url );
+
+		let parsed = Hello::from_url( &url ).expect("signed url doesn't parse");
+		assert_eq!( parsed.peer().0.public_key.q_y, peer.0.public_key.q_y );
+		assert_eq!( sorted_addresses( &parsed ), vec![ "tcp+192.0.2.1:2086", "udp+192.0.2.1:2086" ] );
+
+		assert!( Hello::from_url( "gnunet://hello/malformed" ).is_none() );
+	}
+
+	#[test]
+	fn stored_hellos_round_trip_through_message_buffers() {
+		let ( key, peer ) = peer_key();
+		let message = hello_with_addresses( &peer ).to_message( &key, Duration::from_secs( 3600 ) );
+
+		// The buffer starts with the header of the message, in network byte order.
+		let buffer = message_buffer( &message ).unwrap();
+		let size = mem::size_of::<GNUNET_MessageHeader>() + message.payload.len();
+		assert_eq!( u16::from_be( buffer[0] ) as usize, size );
+		assert_eq!( u16::from_be( buffer[1] ), message.type_ );
+		assert!( buffer.len() * 2 >= size );
+
+		// PEERSTORE keeps the whole message, without the padding of the buffer.
+		let stored = unsafe { slice::from_raw_parts( buffer.as_ptr() as *const u8, size ) }.to_vec();
+		let parsed = Hello::from_stored( &stored ).expect("stored hello doesn't parse");
+		assert_eq!( parsed.peer().0.public_key.q_y, peer.0.public_key.q_y );
+		assert_eq!( sorted_addresses( &parsed ), vec![ "tcp+192.0.2.1:2086", "udp+192.0.2.1:2086" ] );
+
+		assert!( Hello::from_message( &message ).is_some() );
+	}
+
+	#[test]
+	fn stored_hellos_with_wrong_sizes_are_rejected() {
+		let ( key, peer ) = peer_key();
+		let message = hello_with_addresses( &peer ).to_message( &key, Duration::from_secs( 3600 ) );
+		let buffer = message_buffer( &message ).unwrap();
+		let size = u16::from_be( buffer[0] ) as usize;
+		let stored = unsafe { slice::from_raw_parts( buffer.as_ptr() as *const u8, size ) }.to_vec();
+
+		assert!( Hello::from_stored( &stored[..size - 1] ).is_none() );
+		assert!( Hello::from_stored( &stored[..2] ).is_none() );
+		assert!( Hello::from_stored( &[] ).is_none() );
+	}
+
+	#[test]
+	fn oversized_messages_are_rejected() {
+		let message = mq::Message { type_: 1, payload: vec![0u8; u16::MAX as usize] };
+
+		assert!( message_buffer( &message ).is_none() );
+		assert!( Hello::from_message( &message ).is_none() );
+	}
+
+	#[test]
+	fn hellos_are_kept_under_the_key_of_gnunet() {
+		assert_eq!( hello_key(), "peerstore-peer-hello-uri" );
+	}
+}
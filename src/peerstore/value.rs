@@ -0,0 +1,463 @@
+//! Typed values for PEERSTORE records.
+//!
+//! Values that are stored with `Handle::store_typed` start with a header that describes them, so that they can be checked when they are read back.
+//! The header consists of a format byte, the length of the type name in a byte, the type name itself and a 16-bit version number in network byte order.
+//! The encoded value follows the header.
+
+use std::{
+	convert::TryInto,
+	error, fmt,
+	mem
+};
+
+use gnunet_sys::*;
+
+use crate::crypto::{HashCode, PeerIdentity};
+use crate::time::Absolute;
+use super::{Handle, IterateContext, IterateError, StoreContext, StoreOption};
+
+
+
+/// The format of the header, which is increased when the header itself changes.
+const HEADER_FORMAT: u8 = 1;
+
+/// A value that can be stored in a PEERSTORE record.
+pub trait PeerstoreValue: Sized {
+
+	/// The name that identifies the type of the stored value.
+	const TYPE_NAME: &'static str;
+	/// The version of the encoding, which needs to be increased whenever it changes incompatibly.
+	const VERSION: u16 = 1;
+
+	/// Returns `None` if the bytes are malformed.
+	fn from_bytes( bytes: &[u8] ) -> Option<Self>;
+
+	/// Returns `None` if the value can't be encoded.
+	fn to_bytes( &self ) -> Option<Vec<u8>>;
+}
+
+/// A value that is stored as JSON, for any type that can be (de)serialized with serde.
+#[cfg(feature = "serde")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Json<T> ( pub T );
+
+/// A namespace for keys within a subsystem, so that multiple users of the same subsystem don't overwrite each other's records.
+/// The keys of the namespace are prefixed with its name and a slash.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Namespace {
+	subsystem: String,
+	prefix: String
+}
+
+/// A record of which the value has been decoded, with the key in its namespace.
+#[derive(Clone)]
+pub struct TypedRecord<T> {
+	pub peer: PeerIdentity,
+	pub key: String,
+	/// The decoded value, or why it couldn't be decoded.
+	pub value: Result<T, ValueError>,
+	pub expiry: Absolute
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValueError {
+	/// The header is missing or malformed.
+	InvalidHeader,
+	/// The value is of another type.
+	WrongType {
+		expected: &'static str,
+		found: String
+	},
+	/// The value has been encoded with another version of its type.
+	WrongVersion {
+		expected: u16,
+		found: u16
+	},
+	/// The value following the header is malformed.
+	InvalidValue,
+	/// The value can't be encoded, so it hasn't been stored.
+	Unencodable
+}
+
+
+
+impl Handle {
+
+	/// Iterates over the typed records of the namespace, optionally only those of the given peer and/or key.
+	/// Records of the subsystem that are not in the namespace are skipped.
+	/// Keys that aren't valid UTF-8 are given with their invalid parts replaced by `U+FFFD`, like `Record::key` does.
+	pub fn iterate_typed<T, C>( &self, namespace: &Namespace, peer: Option<&PeerIdentity>, key: Option<&str>, mut on_record: C ) -> IterateContext<'_> where
+		T: PeerstoreValue,
+		C: FnMut(Option<Result<TypedRecord<T>, IterateError>>) + 'static
+	{
+		let full_key = key.map( |k| namespace.key( k ) );
+		let namespace2 = namespace.clone();
+
+		self.iterate( &namespace.subsystem, peer, full_key.as_deref(), move |result| match result {
+			Some( Ok( record ) ) => if let Some( key ) = namespace2.strip( &record.key() ) {
+				on_record( Some( Ok( TypedRecord {
+					peer: record.peer(),
+					key: key.to_owned(),
+					value: decode_value( record.value() ),
+					expiry: record.expiry()
+				} ) ) );
+			},
+			Some( Err( e ) ) => on_record( Some( Err( e ) ) ),
+			None => on_record( None )
+		} )
+	}
+
+	/// Stores a typed value under a key of the namespace.
+	/// Fails without storing anything if the value can't be encoded.
+	pub fn store_typed<T, H>( &self, namespace: &Namespace, peer: &PeerIdentity, key: &str, value: &T, expiry: Absolute, options: StoreOption, on_complete: H ) -> Result<StoreContext, ValueError> where
+		T: PeerstoreValue,
+		H: FnOnce(bool) + 'static
+	{
+		let bytes = encode_value( value )?;
+		Ok( self.store( &namespace.subsystem, peer, &namespace.key( key ), bytes, expiry, options, on_complete ) )
+	}
+}
+
+impl Namespace {
+
+	/// # Panics
+	/// When the name contains a slash.
+	pub fn new( subsystem: &str, name: &str ) -> Self {
+		assert!( !name.contains( '/' ), "slash in namespace name" );
+
+		Self {
+			subsystem: subsystem.to_owned(),
+			prefix: format!( "{}/", name )
+		}
+	}
+
+	/// The key of the record that a key of the namespace is stored under.
+	pub fn key( &self, key: &str ) -> String {
+		format!( "{}{}", self.prefix, key )
+	}
+
+	pub fn name( &self ) -> &str {
+		&self.prefix[..self.prefix.len() - 1]
+	}
+
+	/// Returns the key within the namespace, or `None` if the key of the record isn't in the namespace.
+	pub fn strip<'a>( &self, key: &'a str ) -> Option<&'a str> {
+		key.strip_prefix( self.prefix.as_str() )
+	}
+
+	pub fn subsystem( &self ) -> &str {
+		&self.subsystem
+	}
+}
+
+impl fmt::Display for ValueError {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ValueError::InvalidHeader => write!(f, "invalid value header"),
+			ValueError::WrongType { expected, found } => write!(f, "expected value of type {}, found {}", expected, found),
+			ValueError::WrongVersion { expected, found } => write!(f, "expected value of version {}, found {}", expected, found),
+			ValueError::InvalidValue => write!(f, "invalid value"),
+			ValueError::Unencodable => write!(f, "unable to encode value")
+		}
+	}
+}
+
+impl error::Error for ValueError {}
+
+impl PeerstoreValue for HashCode {
+	const TYPE_NAME: &'static str = "hash";
+
+	fn from_bytes( bytes: &[u8] ) -> Option<Self> {
+		if bytes.len() != mem::size_of::<GNUNET_HashCode>() {
+			return None
+		}
+
+		let mut hash = HashCode::new();
+		for ( word, chunk ) in hash.raw_data_mut().iter_mut().zip( bytes.chunks_exact( 4 ) ) {
+			*word = u32::from_ne_bytes( chunk.try_into().unwrap() );
+		}
+		Some( hash )
+	}
+
+	fn to_bytes( &self ) -> Option<Vec<u8>> {
+		Some( self.raw_data().iter().flat_map( |word| word.to_ne_bytes() ).collect() )
+	}
+}
+
+impl PeerstoreValue for PeerIdentity {
+	const TYPE_NAME: &'static str = "peer";
+
+	fn from_bytes( bytes: &[u8] ) -> Option<Self> {
+		let q_y = bytes.try_into().ok()?;
+
+		Some( PeerIdentity::from_inner( GNUNET_PeerIdentity {
+			public_key: GNUNET_CRYPTO_EddsaPublicKey { q_y }
+		} ) )
+	}
+
+	fn to_bytes( &self ) -> Option<Vec<u8>> {
+		Some( self.0.public_key.q_y.to_vec() )
+	}
+}
+
+impl PeerstoreValue for String {
+	const TYPE_NAME: &'static str = "string";
+
+	fn from_bytes( bytes: &[u8] ) -> Option<Self> {
+		String::from_utf8( bytes.to_vec() ).ok()
+	}
+
+	fn to_bytes( &self ) -> Option<Vec<u8>> {
+		Some( self.as_bytes().to_vec() )
+	}
+}
+
+impl PeerstoreValue for Vec<u8> {
+	const TYPE_NAME: &'static str = "bytes";
+
+	fn from_bytes( bytes: &[u8] ) -> Option<Self> {
+		Some( bytes.to_vec() )
+	}
+
+	fn to_bytes( &self ) -> Option<Vec<u8>> {
+		Some( self.clone() )
+	}
+}
+
+/// Integers are stored in network byte order.
+macro_rules! impl_integer_value {
+	( $( $t:ty => $name:expr ),* ) => { $(
+		impl PeerstoreValue for $t {
+			const TYPE_NAME: &'static str = $name;
+
+			fn from_bytes( bytes: &[u8] ) -> Option<Self> {
+				Some( <$t>::from_be_bytes( bytes.try_into().ok()? ) )
+			}
+
+			fn to_bytes( &self ) -> Option<Vec<u8>> {
+				Some( self.to_be_bytes().to_vec() )
+			}
+		}
+	)* };
+}
+
+impl_integer_value!(
+	u8 => "u8", u16 => "u16", u32 => "u32", u64 => "u64",
+	i8 => "i8", i16 => "i16", i32 => "i32", i64 => "i64"
+);
+
+#[cfg(feature = "serde")]
+impl<T> PeerstoreValue for Json<T> where
+	T: serde::Serialize + serde::de::DeserializeOwned
+{
+	const TYPE_NAME: &'static str = "json";
+
+	fn from_bytes( bytes: &[u8] ) -> Option<Self> {
+		serde_json::from_slice( bytes ).ok().map( Json )
+	}
+
+	/// Fails when the value can't be represented as JSON, like maps of which the keys aren't strings.
+	fn to_bytes( &self ) -> Option<Vec<u8>> {
+		serde_json::to_vec( &self.0 ).ok()
+	}
+}
+
+
+
+/// Decodes a value that has been encoded with `encode_value`, after checking its type and version.
+pub fn decode_value<T>( bytes: &[u8] ) -> Result<T, ValueError> where
+	T: PeerstoreValue
+{
+	if bytes.len() < 2 || bytes[0] != HEADER_FORMAT {
+		return Err( ValueError::InvalidHeader )
+	}
+
+	let name_end = 2 + bytes[1] as usize;
+	if bytes.len() < name_end + 2 {
+		return Err( ValueError::InvalidHeader )
+	}
+
+	let name = String::from_utf8_lossy( &bytes[2..name_end] );
+	if name != T::TYPE_NAME {
+		return Err( ValueError::WrongType { expected: T::TYPE_NAME, found: name.into_owned() } )
+	}
+
+	let version = u16::from_be_bytes( bytes[name_end..name_end + 2].try_into().unwrap() );
+	if version != T::VERSION {
+		return Err( ValueError::WrongVersion { expected: T::VERSION, found: version } )
+	}
+
+	T::from_bytes( &bytes[name_end + 2..] ).ok_or( ValueError::InvalidValue )
+}
+
+/// Encodes a value with a header that describes it.
+/// Fails if the value itself can't be encoded.
+///
+/// # Panics
+/// When the type name is longer than 255 bytes.
+pub fn encode_value<T>( value: &T ) -> Result<Vec<u8>, ValueError> where
+	T: PeerstoreValue
+{
+	let name = T::TYPE_NAME.as_bytes();
+	assert!( name.len() <= u8::MAX as usize, "type name too long" );
+
+	let payload = value.to_bytes().ok_or( ValueError::Unencodable )?;
+	let mut bytes = Vec::with_capacity( 4 + name.len() + payload.len() );
+	bytes.push( HEADER_FORMAT );
+	bytes.push( name.len() as u8 );
+	bytes.extend_from_slice( name );
+	bytes.extend_from_slice( &T::VERSION.to_be_bytes() );
+	bytes.extend_from_slice( &payload );
+	Ok( bytes )
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A type of which the encoding has changed, with the same name as `OldCounter`.
+	#[derive(Debug, PartialEq)]
+	struct Counter ( u32 );
+
+	struct OldCounter ( u16 );
+
+	impl PeerstoreValue for Counter {
+		const TYPE_NAME: &'static str = "counter";
+		const VERSION: u16 = 2;
+
+		fn from_bytes( bytes: &[u8] ) -> Option<Self> {
+			u32::from_bytes( bytes ).map( Counter )
+		}
+
+		fn to_bytes( &self ) -> Option<Vec<u8>> {
+			self.0.to_bytes()
+		}
+	}
+
+	impl PeerstoreValue for OldCounter {
+		const TYPE_NAME: &'static str = "counter";
+
+		fn from_bytes( bytes: &[u8] ) -> Option<Self> {
+			u16::from_bytes( bytes ).map( OldCounter )
+		}
+
+		fn to_bytes( &self ) -> Option<Vec<u8>> {
+			self.0.to_bytes()
+		}
+	}
+
+	fn round_trip<T: PeerstoreValue>( value: &T ) -> T {
+		decode_value( &encode_value( value ).expect("value doesn't encode") ).expect("encoded value doesn't decode")
+	}
+
+	#[test]
+	fn every_value_type_round_trips() {
+		let hash = HashCode::generate( b"value" );
+		assert_eq!( round_trip( &hash ).raw_data(), hash.raw_data() );
+
+		let peer = PeerIdentity::from_bytes( &[7u8; 32] ).unwrap();
+		assert_eq!( round_trip( &peer ).to_bytes(), peer.to_bytes() );
+
+		assert_eq!( round_trip( &"välue".to_owned() ), "välue" );
+		assert_eq!( round_trip( &String::new() ), "" );
+		assert_eq!( round_trip( &vec![0u8, 1, 255] ), vec![0u8, 1, 255] );
+		assert_eq!( round_trip( &Vec::<u8>::new() ), Vec::<u8>::new() );
+
+		assert_eq!( round_trip( &u8::MAX ), u8::MAX );
+		assert_eq!( round_trip( &u16::MAX ), u16::MAX );
+		assert_eq!( round_trip( &u32::MAX ), u32::MAX );
+		assert_eq!( round_trip( &u64::MAX ), u64::MAX );
+		assert_eq!( round_trip( &i8::MIN ), i8::MIN );
+		assert_eq!( round_trip( &i16::MIN ), i16::MIN );
+		assert_eq!( round_trip( &i32::MIN ), i32::MIN );
+		assert_eq!( round_trip( &i64::MIN ), i64::MIN );
+
+		assert_eq!( round_trip( &Counter ( 3 ) ), Counter ( 3 ) );
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn json_values_round_trip() {
+		let value = Json ( vec![ ( "a".to_owned(), 1u32 ), ( "b".to_owned(), 2 ) ] );
+		assert_eq!( round_trip( &value ), value );
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn values_that_are_not_json_are_rejected() {
+		// JSON objects can only have strings as keys.
+		let value = Json ( vec![ ( ( 1u8, 2u8 ), 3u32 ) ].into_iter().collect::<std::collections::BTreeMap<_, _>>() );
+
+		assert_eq!( encode_value( &value ), Err( ValueError::Unencodable ) );
+	}
+
+	#[test]
+	fn integers_are_stored_in_network_byte_order() {
+		let bytes = encode_value( &0x0102_u16 ).unwrap();
+		assert_eq!( &bytes[bytes.len() - 2..], &[1, 2] );
+	}
+
+	#[test]
+	fn values_of_other_types_are_rejected() {
+		let bytes = encode_value( &"text".to_owned() ).unwrap();
+
+		assert_eq!( decode_value::<u32>( &bytes ), Err( ValueError::WrongType { expected: "u32", found: "string".to_owned() } ) );
+	}
+
+	#[test]
+	fn values_of_other_versions_are_rejected() {
+		let bytes = encode_value( &OldCounter ( 3 ) ).unwrap();
+
+		assert_eq!( decode_value::<Counter>( &bytes ), Err( ValueError::WrongVersion { expected: 2, found: 1 } ) );
+	}
+
+	#[test]
+	fn truncated_headers_are_rejected() {
+		let bytes = encode_value( &5u32 ).unwrap();
+		let header_size = 2 + u32::TYPE_NAME.len() + 2;
+
+		for size in 0..header_size {
+			assert_eq!( decode_value::<u32>( &bytes[..size] ), Err( ValueError::InvalidHeader ), "header truncated to {} bytes", size );
+		}
+		assert_eq!( decode_value::<u32>( &bytes[..header_size] ), Err( ValueError::InvalidValue ) );
+	}
+
+	#[test]
+	fn headers_of_other_formats_are_rejected() {
+		let mut bytes = encode_value( &5u32 ).unwrap();
+		bytes[0] = HEADER_FORMAT + 1;
+
+		assert_eq!( decode_value::<u32>( &bytes ), Err( ValueError::InvalidHeader ) );
+	}
+
+	#[test]
+	fn malformed_values_are_rejected() {
+		let mut bytes = encode_value( &5u32 ).unwrap();
+		bytes.push( 0 );
+
+		assert_eq!( decode_value::<u32>( &bytes ), Err( ValueError::InvalidValue ) );
+	}
+
+	#[test]
+	fn namespaces_strip_their_own_prefix_only() {
+		let namespace = Namespace::new( "subsystem", "app" );
+
+		assert_eq!( namespace.key( "key" ), "app/key" );
+		assert_eq!( namespace.strip( "app/key" ), Some( "key" ) );
+		assert_eq!( namespace.strip( "app/" ), Some( "" ) );
+		assert_eq!( namespace.strip( "app/nested/key" ), Some( "nested/key" ) );
+		assert_eq!( namespace.strip( "app" ), None );
+		assert_eq!( namespace.strip( "application/key" ), None );
+		assert_eq!( namespace.strip( "other/key" ), None );
+		assert_eq!( namespace.name(), "app" );
+	}
+
+	#[test]
+	#[should_panic( expected = "slash in namespace name" )]
+	fn namespace_names_cant_contain_slashes() {
+		Namespace::new( "subsystem", "app/nested" );
+	}
+}
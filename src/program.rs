@@ -1,9 +1,11 @@
-use crate::configuration;
+use crate::configuration::ConfigurationRef;
 
 use std::{
+	collections::HashMap,
 	env,
-	ffi::CString,
-	mem::MaybeUninit,
+	ffi::{CStr, CString},
+	mem,
+	path::PathBuf,
 	ptr,
 	os::raw::*
 };
@@ -13,58 +15,300 @@ use gnunet_sys::*;
 
 pub type GenericReturnValue = GNUNET_GenericReturnValue;
 
+/// The command line arguments that were given to the program, after Gnunet has parsed them.
+pub struct Arguments {
+	/// The remaining positional arguments.
+	pub args: Vec<String>,
+	/// The configuration file that was given with `-c`, if any.
+	pub cfgfile: Option<String>,
+	values: HashMap<String, OptionValue>
+}
+
+/// Builds the command line options of a program, and runs it.
+pub struct Builder {
+	binary_name: CString,
+	helptext: CString,
+	without_scheduler: bool,
+	options: Vec<OptionSpec>
+}
+
+/// The parsed value of a command line option.
+#[derive(Clone, Debug)]
+pub enum OptionValue {
+	Flag( bool ),
+	String( Option<String> ),
+	Uint( u32 ),
+	Filename( Option<PathBuf> )
+}
+
+struct MainData<F> {
+	main: Option<F>,
+	options: *const Vec<OptionSpec>
+}
+
+struct OptionSpec {
+	short_name: char,
+	name: CString,
+	argument_help: CString,
+	description: CString,
+	mandatory: bool,
+	exclusive: bool,
+	storage: OptionStorage
+}
+
+enum OptionStorage {
+	Flag( Box<c_int> ),
+	String( Box<*mut c_char> ),
+	Uint( Box<c_uint> ),
+	Filename( Box<*mut c_char> )
+}
+
 
 
 pub fn run<F>( binary_name: &str, helptext: &str, main: F ) -> GenericReturnValue where
-	F: FnOnce(configuration::Handle)
+	F: FnOnce(&ConfigurationRef)
 {
 	run2( binary_name, helptext, false, main )
 }
 
 pub fn run2<F>( binary_name: &str, helptext: &str, without_scheduler: bool, main: F ) -> GenericReturnValue where
-	F: FnOnce(configuration::Handle)
+	F: FnOnce(&ConfigurationRef)
 {
-	let cbinary_name = CString::new(binary_name).expect("null character in binary name");
-	let chelptext = CString::new(helptext).expect("null character in helptext");
-
-	let closure = Box::into_raw( Box::new( main ) );
-
-	let first_arg = CString::new( env::args().next().unwrap() ).unwrap();
-	let empty = CString::new("").unwrap();
-	let mut option: GNUNET_GETOPT_CommandLineOption = unsafe { MaybeUninit::uninit().assume_init() };
-	option.shortName = 'a' as _;
-	option.name = ptr::null(); //empty.as_ptr();
-	option.argumentHelp = empty.as_ptr();
-	option.description = empty.as_ptr();
-	option.require_argument = GNUNET_GenericReturnValue_GNUNET_NO;
-	option.option_mandatory = GNUNET_GenericReturnValue_GNUNET_NO;
-	option.option_exclusive = GNUNET_GenericReturnValue_GNUNET_NO;
-	option.processor = Some( ffi_command_processor );
-	option.cleaner = Some( ffi_cleaner );
-
-	let e = first_arg.as_ptr() as *mut c_char;
-
-	let cwithout_scheduler = if without_scheduler { GNUNET_GenericReturnValue_GNUNET_YES } else { GNUNET_GenericReturnValue_GNUNET_NO };
-
-	// TODO: convert to C compatible argc and argv.
-	unsafe { GNUNET_PROGRAM_run2( 1, &e as _, cbinary_name.as_ptr(), chelptext.as_ptr(), &option, Some( ffi_main::<F> ), closure as _, cwithout_scheduler ) }
+	Builder::new( binary_name, helptext )
+		.without_scheduler( without_scheduler )
+		.run( |config, _| main( config ) )
 }
 
 
 
-extern "C" fn ffi_cleaner(_data: *mut c_void) {}
+impl Arguments {
+
+	/// Whether the flag with the given long name has been set.
+	pub fn flag( &self, name: &str ) -> bool {
+		match self.values.get( name ) {
+			Some( OptionValue::Flag( value ) ) => *value,
+			_ => false
+		}
+	}
+
+	/// The filename given to the option with the given long name, with `~` expanded.
+	pub fn filename( &self, name: &str ) -> Option<&PathBuf> {
+		match self.values.get( name ) {
+			Some( OptionValue::Filename( value ) ) => value.as_ref(),
+			_ => None
+		}
+	}
 
-extern "C" fn ffi_command_processor( ctx: *mut GNUNET_GETOPT_CommandLineProcessorContext, scls: *mut c_void, option: *const c_char, value: *const c_char) -> c_int {
-	eprintln!("ffi_command_processor"); 0
+	/// The string given to the option with the given long name.
+	pub fn string( &self, name: &str ) -> Option<&str> {
+		match self.values.get( name ) {
+			Some( OptionValue::String( value ) ) => value.as_ref().map( |s| s.as_str() ),
+			_ => None
+		}
+	}
+
+	/// The number given to the option with the given long name, or its default value.
+	pub fn uint( &self, name: &str ) -> Option<u32> {
+		match self.values.get( name ) {
+			Some( OptionValue::Uint( value ) ) => Some( *value ),
+			_ => None
+		}
+	}
+
+	/// The parsed value of the option with the given long name.
+	pub fn value( &self, name: &str ) -> Option<&OptionValue> {
+		self.values.get( name )
+	}
 }
 
+impl Builder {
+
+	pub fn new( binary_name: &str, helptext: &str ) -> Self {
+		Self {
+			binary_name: CString::new(binary_name).expect("null character in binary name"),
+			helptext: CString::new(helptext).expect("null character in helptext"),
+			without_scheduler: false,
+			options: Vec::new()
+		}
+	}
+
+	fn add_option( mut self, short_name: char, name: &str, argument_help: &str, description: &str, storage: OptionStorage ) -> Self {
+		assert!( short_name.is_ascii(), "short option name must be an ASCII character" );
+
+		self.options.push( OptionSpec {
+			short_name,
+			name: CString::new(name).expect("null character in option name"),
+			argument_help: CString::new(argument_help).expect("null character in argument help"),
+			description: CString::new(description).expect("null character in option description"),
+			mandatory: false,
+			exclusive: false,
+			storage
+		} );
+		self
+	}
+
+	/// Marks the last added option as one that may not be combined with other options.
+	pub fn exclusive( mut self ) -> Self {
+		self.options.last_mut().expect("no option added yet").exclusive = true;
+		self
+	}
+
+	/// Marks the last added option as one that needs to be given.
+	pub fn mandatory( mut self ) -> Self {
+		self.options.last_mut().expect("no option added yet").mandatory = true;
+		self
+	}
+
+	/// Adds an option that takes a filename, in which `~` will be expanded.
+	pub fn option_filename( self, short_name: char, name: &str, argument_help: &str, description: &str ) -> Self {
+		self.add_option( short_name, name, argument_help, description, OptionStorage::Filename( Box::new( ptr::null_mut() ) ) )
+	}
+
+	/// Adds an option that doesn't take an argument.
+	pub fn option_flag( self, short_name: char, name: &str, description: &str ) -> Self {
+		self.add_option( short_name, name, "", description, OptionStorage::Flag( Box::new( 0 ) ) )
+	}
+
+	/// Adds an option that takes a string.
+	pub fn option_string( self, short_name: char, name: &str, argument_help: &str, description: &str ) -> Self {
+		self.add_option( short_name, name, argument_help, description, OptionStorage::String( Box::new( ptr::null_mut() ) ) )
+	}
+
+	/// Adds an option that takes an unsigned number, which will be `default` if the option is not given.
+	pub fn option_uint( self, short_name: char, name: &str, argument_help: &str, description: &str, default: u32 ) -> Self {
+		self.add_option( short_name, name, argument_help, description, OptionStorage::Uint( Box::new( default as _ ) ) )
+	}
+
+	/// Parses the command line arguments of this process, loads the configuration and calls `main` with both.
+	/// Gnunet's standard options (like `-c`, `-h` and `-L`) are always available.
+	pub fn run<F>( mut self, main: F ) -> GenericReturnValue where
+		F: FnOnce(&ConfigurationRef, Arguments)
+	{
+		let mut coptions: Vec<GNUNET_GETOPT_CommandLineOption> = self.options.iter_mut().map( |o| o.to_inner() ).collect();
+		coptions.push( unsafe { mem::zeroed() } );
+
+		let cargs: Vec<CString> = env::args().map( |a| CString::new(a).expect("null character in argument") ).collect();
+		let mut argv: Vec<*mut c_char> = cargs.iter().map( |a| a.as_ptr() as *mut c_char ).collect();
+		argv.push( ptr::null_mut() );
+
+		let closure = Box::into_raw( Box::new( MainData {
+			main: Some( main ),
+			options: &self.options as _
+		} ) );
+
+		let cwithout_scheduler = if self.without_scheduler { GNUNET_GenericReturnValue_GNUNET_YES } else { GNUNET_GenericReturnValue_GNUNET_NO };
+
+		let result = unsafe { GNUNET_PROGRAM_run2(
+			cargs.len() as _,
+			argv.as_ptr(),
+			self.binary_name.as_ptr(),
+			self.helptext.as_ptr(),
+			coptions.as_ptr(),
+			Some( ffi_main::<F> ),
+			closure as _,
+			cwithout_scheduler
+		) };
+
+		// `ffi_main` only takes `main` out, because it doesn't get called when parsing fails or when only the help text is requested.
+		unsafe { drop( Box::from_raw( closure ) ) };
+		result
+	}
+
+	/// Runs `main` without starting the scheduler.
+	pub fn without_scheduler( mut self, value: bool ) -> Self {
+		self.without_scheduler = value;
+		self
+	}
+}
+
+impl OptionSpec {
+
+	fn to_inner( &mut self ) -> GNUNET_GETOPT_CommandLineOption {
+		let short_name = self.short_name as c_char;
+
+		let mut inner = unsafe { match &mut self.storage {
+			OptionStorage::Flag( value ) =>
+				GNUNET_GETOPT_option_flag( short_name, self.name.as_ptr(), self.description.as_ptr(), &mut **value as _ ),
+			OptionStorage::String( value ) =>
+				GNUNET_GETOPT_option_string( short_name, self.name.as_ptr(), self.argument_help.as_ptr(), self.description.as_ptr(), &mut **value as _ ),
+			OptionStorage::Uint( value ) =>
+				GNUNET_GETOPT_option_uint( short_name, self.name.as_ptr(), self.argument_help.as_ptr(), self.description.as_ptr(), &mut **value as _ ),
+			OptionStorage::Filename( value ) =>
+				GNUNET_GETOPT_option_filename( short_name, self.name.as_ptr(), self.argument_help.as_ptr(), self.description.as_ptr(), &mut **value as _ )
+		} };
+
+		if self.mandatory {
+			inner = unsafe { GNUNET_GETOPT_option_mandatory( inner ) };
+		}
+		if self.exclusive {
+			inner = unsafe { GNUNET_GETOPT_option_exclusive( inner ) };
+		}
+		inner
+	}
+
+	unsafe fn value( &self ) -> OptionValue {
+		match &self.storage {
+			OptionStorage::Flag( value ) => OptionValue::Flag( **value != 0 ),
+			OptionStorage::String( value ) => OptionValue::String( string_from_ptr( **value ) ),
+			OptionStorage::Uint( value ) => OptionValue::Uint( **value as _ ),
+			OptionStorage::Filename( value ) => OptionValue::Filename( string_from_ptr( **value ).map( PathBuf::from ) )
+		}
+	}
+}
+
+impl Drop for OptionSpec {
+
+	fn drop( &mut self ) {
+		// The option parser allocates the strings that it stores.
+		match &self.storage {
+			OptionStorage::String( value ) | OptionStorage::Filename( value ) => if **value != ptr::null_mut() {
+				unsafe { GNUNET_free( **value as _ ) };
+			},
+			_ => {}
+		}
+	}
+}
+
+
+
+unsafe fn string_from_ptr( ptr: *const c_char ) -> Option<String> {
+	if ptr == ptr::null() {
+		None
+	}
+	else {
+		Some( CStr::from_ptr( ptr ).to_string_lossy().into_owned() )
+	}
+}
+
+
+
 unsafe extern "C" fn ffi_main<F>(data: *mut c_void, args: *const *mut c_char, cfgfile: *const c_char, cfg: *const GNUNET_CONFIGURATION_Handle)
-	where F: FnOnce(configuration::Handle)
+	where F: FnOnce(&ConfigurationRef, Arguments)
 {
-	let closure: Box<F> = Box::from_raw( data as _ );
+	let data = &mut *(data as *mut MainData<F>);
 
 	assert!(cfg != ptr::null(), "no configuration file");
 
-	let configuration = configuration::Handle::from_inner( cfg as _ );
-	closure( configuration );
-}
\ No newline at end of file
+	let mut remaining = Vec::new();
+	if args != ptr::null() {
+		let mut i = 0;
+		while *args.offset( i ) != ptr::null_mut() {
+			remaining.push( CStr::from_ptr( *args.offset( i ) ).to_string_lossy().into_owned() );
+			i += 1;
+		}
+	}
+
+	let values = (*data.options).iter()
+		.map( |o| ( o.name.to_string_lossy().into_owned(), o.value() ) )
+		.collect();
+
+	let arguments = Arguments {
+		args: remaining,
+		cfgfile: string_from_ptr( cfgfile ),
+		values
+	};
+
+	let configuration = ConfigurationRef::from_ptr( cfg );
+	let main = data.main.take().expect("main called twice");
+	main( configuration, arguments );
+}
@@ -3,16 +3,480 @@
 //! The Gnunet API control flow works by giving it closures that execute on specific events.
 //! For example, you want to send a message, and a closure will be called upon completion.
 //! This control flow is very useful because you don't want to be waiting on a lot of the tasks you'll be doing.
-//! 
+//!
 //! Nevertheless, it is still usefull to control the execution of your own program.
 //! For those cases, this runtime can be used.
 //! Also, the runtime enables the programmer to make use of Rust's async/await syntax.
 //! All it does is that it runs the Gnunet loop on a seperate thread, and all related functions pass the code to that loop, which executes it.
-//! 
-//! TODO: Implement
+//!
+//! Work is handed to the Gnunet thread through a queue.
+//! Every time something is added to the queue, a byte is written to a socket pair of which the other end is watched by the Gnunet scheduler.
+//! This way, the scheduler wakes up and executes the queued work in between its own tasks.
+//! Futures are polled on the Gnunet thread as well, so callbacks given to the Gnunet API wake them on the same thread.
+//!
+//! Once the runtime shuts down, or the Gnunet thread stops in any other way, the queue is closed.
+//! Queued work is then dropped, and work that is handed to it afterwards is dropped right away.
+//! Futures that are not `Send` are only ever dropped on the Gnunet thread, and are leaked when that isn't possible anymore.
 
+use gnunet_sys::*;
 
+use std::{
+	collections::VecDeque,
+	future::Future,
+	io::{self, Read, Write},
+	mem::{self, ManuallyDrop},
+	os::{
+		raw::*,
+		unix::{io::AsRawFd, net::UnixStream}
+	},
+	pin::Pin,
+	ptr,
+	sync::{mpsc, Arc, Mutex, PoisonError},
+	task::{Context, Poll, Wake, Waker},
+	thread::{self, ThreadId},
+	time::{Duration, Instant}
+};
 
+use crate::configuration::{Configuration, ConfigurationRef};
+
+
+
+type BoxFuture = Pin<Box<dyn Future<Output=()> + Send>>;
+
+/// How long dropping the runtime waits for the Gnunet thread to finish.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs( 5 );
+/// How often waiting for a future checks whether the Gnunet thread has stopped.
+const WAIT_INTERVAL: Duration = Duration::from_millis( 100 );
+
+/// Runs the Gnunet scheduler on a dedicated thread, and executes closures and futures on it.
 pub struct Runtime {
-	
-}
\ No newline at end of file
+	// Leaked when the Gnunet thread is detached, as it may still be using it.
+	config: ManuallyDrop<Configuration>,
+	shared: Arc<Shared>,
+	thread: Option<thread::JoinHandle<()>>
+}
+
+enum Job {
+	Execute( Box<dyn FnOnce() + Send> ),
+	Poll( Arc<Task> ),
+	Shutdown
+}
+
+/// A future that is created and polled on the Gnunet thread only.
+struct LocalFuture<F> ( F );
+
+struct LoopState {
+	shared: Arc<Shared>,
+	receiver: UnixStream,
+	socket: *mut GNUNET_NETWORK_Handle
+}
+
+struct Shared {
+	/// `None` once the queue has been closed.
+	queue: Mutex<Option<VecDeque<Job>>>,
+	notifier: UnixStream
+}
+
+/// Closes the queue when the Gnunet thread stops, even when it panics.
+struct StopGuard ( Arc<Shared> );
+
+struct Task {
+	future: Mutex<Option<BoxFuture>>,
+	shared: Arc<Shared>,
+	/// The thread that a future that is not `Send` has been created on, which is the only one that may drop it.
+	owner: Option<ThreadId>
+}
+
+
+
+impl Runtime {
+
+	/// Starts the Gnunet scheduler on a new thread.
+	/// The given configuration is made available to the code running on the runtime through [`Runtime::config`].
+	pub fn new( config: Configuration ) -> io::Result<Self> {
+
+		let ( notifier, receiver ) = UnixStream::pair()?;
+		notifier.set_nonblocking( true )?;
+		receiver.set_nonblocking( true )?;
+
+		let shared = Arc::new( Shared {
+			queue: Mutex::new( Some( VecDeque::new() ) ),
+			notifier
+		} );
+
+		let loop_shared = shared.clone();
+		let thread = thread::Builder::new()
+			.name( "gnunet-scheduler".to_owned() )
+			.spawn( move || run_loop( loop_shared, receiver ) )?;
+
+		Ok( Self {
+			config: ManuallyDrop::new( config ),
+			shared,
+			thread: Some( thread )
+		} )
+	}
+
+	/// Runs the future on the Gnunet thread, and blocks the current thread until it has completed.
+	///
+	/// # Panics
+	/// When called from the Gnunet thread itself, because that would deadlock.
+	/// Also when the Gnunet thread stops before the future has completed, in which case the future is dropped.
+	pub fn block_on<F>( &self, future: F ) -> F::Output where
+		F: Future + Send + 'static,
+		F::Output: Send
+	{
+		assert!( !self.is_runtime_thread(), "`block_on` can not be called from within the runtime" );
+
+		let ( sender, receiver ) = mpsc::channel();
+		self.shared.spawn( Box::pin( async move {
+			let _ = sender.send( future.await );
+		} ), None );
+
+		self.wait( receiver )
+	}
+
+	/// Creates a future on the Gnunet thread, runs it there, and blocks the current thread until it has completed.
+	/// This is for futures that are not `Send`, like the ones that use service handles, of which only the output is sent back.
+	///
+	/// # Panics
+	/// When called from the Gnunet thread itself, because that would deadlock.
+	/// Also when the Gnunet thread stops before the future has completed.
+	pub fn block_on_local<C,F>( &self, create: C ) -> F::Output where
+		C: FnOnce() -> F + Send + 'static,
+		F: Future + 'static,
+		F::Output: Send
+	{
+		assert!( !self.is_runtime_thread(), "`block_on_local` can not be called from within the runtime" );
+
+		let ( sender, receiver ) = mpsc::channel();
+		self.spawn_local( move || {
+			let future = create();
+			async move {
+				let _ = sender.send( future.await );
+			}
+		} );
+
+		self.wait( receiver )
+	}
+
+	/// The configuration that this runtime has been created with.
+	pub fn config( &self ) -> &ConfigurationRef {
+		&self.config
+	}
+
+	/// Executes the given closure on the Gnunet thread.
+	/// This is required for any code that calls into the Gnunet API directly.
+	pub fn execute<F>( &self, closure: F ) where
+		F: FnOnce() + Send + 'static
+	{
+		self.shared.push( Job::Execute( Box::new( closure ) ) );
+	}
+
+	/// Waits for the output of a future that runs on the Gnunet thread.
+	///
+	/// The sender is usually dropped together with the future when the Gnunet thread stops.
+	/// But a future that waits on a callback that won't be called anymore is never dropped, or is leaked, so the queue is checked as well.
+	fn wait<T>( &self, receiver: mpsc::Receiver<T> ) -> T {
+		loop {
+			match receiver.recv_timeout( WAIT_INTERVAL ) {
+				Ok( output ) => return output,
+				Err( mpsc::RecvTimeoutError::Disconnected ) => break,
+				Err( mpsc::RecvTimeoutError::Timeout ) => if self.shared.is_closed() {
+					// The output may have been sent right before the queue got closed.
+					if let Ok( output ) = receiver.try_recv() {
+						return output
+					}
+					break
+				}
+			}
+		}
+		panic!("runtime stopped before the future completed")
+	}
+
+	fn is_runtime_thread( &self ) -> bool {
+		match &self.thread {
+			Some( thread ) => thread.thread().id() == thread::current().id(),
+			None => false
+		}
+	}
+
+	/// Runs the future on the Gnunet thread, without waiting for it to complete.
+	pub fn spawn<F>( &self, future: F ) where
+		F: Future<Output=()> + Send + 'static
+	{
+		self.shared.spawn( Box::pin( future ), None );
+	}
+
+	/// Creates a future on the Gnunet thread and runs it there, without waiting for it to complete.
+	/// This is for futures that are not `Send`, like the ones that use service handles.
+	pub fn spawn_local<C,F>( &self, create: C ) where
+		C: FnOnce() -> F + Send + 'static,
+		F: Future<Output=()> + 'static
+	{
+		let shared = self.shared.clone();
+		self.execute( move || {
+			shared.spawn( Box::pin( LocalFuture ( create() ) ), Some( thread::current().id() ) );
+		} );
+	}
+}
+
+impl Drop for Runtime {
+
+	/// Shuts down the Gnunet scheduler and waits for the thread to finish.
+	///
+	/// The scheduler only finishes when no tasks are pending anymore, so all service handles should be disconnected before this.
+	/// If the thread hasn't finished after `SHUTDOWN_TIMEOUT`, because a handle is still connected, it is detached instead, and the configuration is leaked.
+	fn drop( &mut self ) {
+		self.shared.push( Job::Shutdown );
+
+		if let Some( thread ) = self.thread.take() {
+			let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+			while !thread.is_finished() && Instant::now() < deadline {
+				thread::sleep( Duration::from_millis( 10 ) );
+			}
+
+			if !thread.is_finished() {
+				return
+			}
+			let _ = thread.join();
+		}
+
+		unsafe { ManuallyDrop::drop( &mut self.config ) };
+	}
+}
+
+impl<F> Future for LocalFuture<F> where
+	F: Future
+{
+	type Output = F::Output;
+
+	fn poll( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<F::Output> {
+		unsafe { self.map_unchecked_mut( |s| &mut s.0 ) }.poll( cx )
+	}
+}
+
+// Tasks are only ever polled on the Gnunet thread.
+unsafe impl<F> Send for LocalFuture<F> {}
+
+impl Shared {
+
+	/// Closes the queue, and drops the jobs that are still in it.
+	fn close( &self ) {
+		let jobs = self.queue.lock().unwrap().take();
+		drop( jobs );
+	}
+
+	fn is_closed( &self ) -> bool {
+		self.queue.lock().unwrap().is_none()
+	}
+
+	/// Queues the job, or drops it if the queue has been closed.
+	/// Futures that are not `Send` are leaked by their task then, unless this is the Gnunet thread.
+	fn push( &self, job: Job ) {
+		let rejected = match self.queue.lock().unwrap().as_mut() {
+			Some( queue ) => { queue.push_back( job ); None },
+			None => Some( job )
+		};
+
+		// Dropping a rejected job may drop a future, which shouldn't happen while the queue is locked.
+		if rejected.is_some() {
+			return
+		}
+
+		// If the socket buffer is full, the scheduler has a wake up pending already.
+		let _ = (&self.notifier).write( &[1] );
+	}
+
+	fn spawn( self: &Arc<Self>, future: BoxFuture, owner: Option<ThreadId> ) {
+		let task = Arc::new( Task {
+			future: Mutex::new( Some( future ) ),
+			shared: self.clone(),
+			owner
+		} );
+
+		self.push( Job::Poll( task ) );
+	}
+}
+
+impl Drop for StopGuard {
+
+	fn drop( &mut self ) {
+		self.0.close();
+	}
+}
+
+impl Task {
+
+	fn poll( self: &Arc<Self> ) {
+
+		// Move the future out of the task while polling, so that waking it during the poll doesn't block.
+		let future = self.future.lock().unwrap().take();
+
+		if let Some( mut future ) = future {
+			let waker = Waker::from( self.clone() );
+			let mut cx = Context::from_waker( &waker );
+
+			if future.as_mut().poll( &mut cx ).is_pending() {
+				*self.future.lock().unwrap() = Some( future );
+			}
+		}
+	}
+}
+
+impl Drop for Task {
+
+	/// Leaks a future that is not `Send` when the last reference to its task goes away on another thread.
+	/// This happens when a waker is dropped elsewhere, or when it wakes the task after the queue has been closed.
+	fn drop( &mut self ) {
+		if let Some( owner ) = self.owner {
+			if owner != thread::current().id() {
+				let future = self.future.get_mut().unwrap_or_else( PoisonError::into_inner ).take();
+				mem::forget( future );
+			}
+		}
+	}
+}
+
+impl Wake for Task {
+
+	fn wake( self: Arc<Self> ) {
+		let shared = self.shared.clone();
+		shared.push( Job::Poll( self ) );
+	}
+}
+
+
+
+fn run_loop( shared: Arc<Shared>, receiver: UnixStream ) {
+	let _guard = StopGuard ( shared.clone() );
+
+	let socket = unsafe { GNUNET_NETWORK_socket_box_native( receiver.as_raw_fd() ) };
+	assert!( socket != ptr::null_mut(), "unable to box notification socket" );
+
+	let state = Box::into_raw( Box::new( LoopState {
+		shared,
+		receiver,
+		socket
+	} ) );
+
+	unsafe {
+		GNUNET_SCHEDULER_run( Some( ffi_init ), state as _ );
+
+		let state = Box::from_raw( state );
+		GNUNET_NETWORK_socket_free_memory_only_( state.socket );
+	}
+}
+
+unsafe fn schedule_wakeup( state: *mut LoopState ) {
+	GNUNET_SCHEDULER_add_read_net( GNUNET_TIME_relative_get_forever_(), (*state).socket, Some( ffi_wakeup ), state as _ );
+}
+
+
+
+unsafe extern "C" fn ffi_init( cls: *mut c_void ) {
+	schedule_wakeup( cls as _ );
+}
+
+unsafe extern "C" fn ffi_wakeup( cls: *mut c_void ) {
+	let state = &mut *(cls as *mut LoopState);
+
+	// Empty the socket, the queue is what tells us what to do.
+	let mut buffer = [0u8; 64];
+	while let Ok( n ) = state.receiver.read( &mut buffer ) {
+		if n == 0 { break }
+	}
+
+	loop {
+		let job = state.shared.queue.lock().unwrap().as_mut().and_then( |q| q.pop_front() );
+
+		match job {
+			None => break,
+			Some( Job::Execute( closure ) ) => closure(),
+			Some( Job::Poll( task ) ) => task.poll(),
+			Some( Job::Shutdown ) => {
+				state.shared.close();
+				GNUNET_SCHEDULER_shutdown();
+				return
+			}
+		}
+	}
+
+	schedule_wakeup( state );
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{cell::Cell, rc::Rc};
+
+	use crate::scheduler;
+
+	fn runtime() -> Runtime {
+		Runtime::new( Configuration::new() ).expect("unable to start runtime")
+	}
+
+	#[test]
+	fn block_on_returns_the_output() {
+		let runtime = runtime();
+
+		assert_eq!( runtime.block_on( async { 1 + 1 } ), 2 );
+	}
+
+	#[test]
+	fn block_on_local_drives_futures_that_are_not_send() {
+		let runtime = runtime();
+
+		let output = runtime.block_on_local( || {
+			let count = Rc::new( Cell::new( 0 ) );
+			async move {
+				// Waiting on the scheduler requires being woken by it.
+				for _ in 0..3 {
+					scheduler::delay( Duration::from_millis( 1 ) ).await;
+					count.set( count.get() + 1 );
+				}
+				count.get()
+			}
+		} );
+		assert_eq!( output, 3 );
+	}
+
+	#[test]
+	fn work_is_rejected_after_shutdown() {
+		let runtime = runtime();
+		let shared = runtime.shared.clone();
+		drop( runtime );
+
+		// The job is dropped right away, and with it the sender.
+		let ( sender, receiver ) = mpsc::channel::<()>();
+		shared.push( Job::Execute( Box::new( move || drop( sender ) ) ) );
+		assert!( receiver.recv().is_err() );
+	}
+
+	#[test]
+	fn rejected_futures_that_are_not_send_are_leaked() {
+		struct DropFlag ( Arc<Mutex<bool>> );
+
+		impl Drop for DropFlag {
+			fn drop( &mut self ) {
+				*self.0.lock().unwrap() = true;
+			}
+		}
+
+		let runtime = runtime();
+		let shared = runtime.shared.clone();
+		let gnunet_thread = runtime.thread.as_ref().unwrap().thread().id();
+		drop( runtime );
+
+		let dropped = Arc::new( Mutex::new( false ) );
+		let flag = DropFlag ( dropped.clone() );
+		shared.spawn( Box::pin( LocalFuture ( async move { let _ = &flag; } ) ), Some( gnunet_thread ) );
+		assert!( !*dropped.lock().unwrap() );
+
+		// Futures that are `Send` can be dropped anywhere.
+		let flag = DropFlag ( dropped.clone() );
+		shared.spawn( Box::pin( async move { let _ = &flag; } ), None );
+		assert!( *dropped.lock().unwrap() );
+	}
+}
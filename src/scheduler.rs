@@ -1,42 +1,130 @@
 use gnunet_sys::*;
 
 use std::{
+	cell::RefCell,
+	future::Future,
+	pin::Pin,
 	ptr,
-	os::raw::*
+	os::raw::*,
+	rc::Rc,
+	task::{Context, Poll, Waker},
+	time::Duration
 };
 
+use crate::closure;
 
 
-pub struct Task ( *mut GNUNET_SCHEDULER_Task );
+
+pub struct Task {
+	inner: *mut GNUNET_SCHEDULER_Task,
+	closure: closure::Once
+}
+
+/// A future that completes after a delay.
+/// The delay is cancelled when dropped.
+pub struct Delay {
+	task: Option<Task>,
+	state: Rc<RefCell<DelayState>>
+}
+
+#[derive(Default)]
+struct DelayState {
+	elapsed: bool,
+	waker: Option<Waker>
+}
 
 
 
+/// Runs the task after the given delay.
+pub fn add_delayed<T>( delay: Duration, task: T ) -> Task where
+	T: FnOnce() + 'static
+{
+	let closure = closure::Once::new( task );
+	let cdelay = GNUNET_TIME_Relative { rel_value_us: delay.as_micros() as _ };
+
+	let inner = unsafe { GNUNET_SCHEDULER_add_delayed( cdelay, Some( ffi_task_callback::<T> ), closure.cls() ) };
+	assert!(inner != ptr::null_mut(), "GNUNET_SCHEDULER_add_delayed returned NULL pointer");
+	Task { inner, closure }
+}
+
 pub fn add_now<T>( task: T ) -> Task where
-	T: FnOnce()
+	T: FnOnce() + 'static
 {
-	let cls = Box::into_raw( Box::new( task ) );
+	let closure = closure::Once::new( task );
 
-	let inner = unsafe { GNUNET_SCHEDULER_add_now( Some( ffi_task_callback::<T> ), cls as _ ) };
+	let inner = unsafe { GNUNET_SCHEDULER_add_now( Some( ffi_task_callback::<T> ), closure.cls() ) };
 	assert!(inner != ptr::null_mut(), "GNUNET_SCHEDULER_add_now returned NULL pointer");
-	Task ( inner )
+	Task { inner, closure }
 }
 
 pub fn add_shutdown<T>( task: T ) -> Task where
-	T: FnOnce()
+	T: FnOnce() + 'static
 {
-	let cls = Box::into_raw( Box::new( task ) );
+	let closure = closure::Once::new( task );
 
-	let inner = unsafe { GNUNET_SCHEDULER_add_shutdown( Some( ffi_task_callback::<T> ), cls as _ ) };
+	let inner = unsafe { GNUNET_SCHEDULER_add_shutdown( Some( ffi_task_callback::<T> ), closure.cls() ) };
 	assert!(inner != ptr::null_mut(), "GNUNET_SCHEDULER_add_shutdown returned NULL pointer");
-	Task ( inner )
+	Task { inner, closure }
+}
+
+/// Returns a future that completes after the given delay.
+pub fn delay( duration: Duration ) -> Delay {
+	let state = Rc::new( RefCell::new( DelayState::default() ) );
+	let task_state = state.clone();
+
+	let task = add_delayed( duration, move || {
+		let mut state = task_state.borrow_mut();
+		state.elapsed = true;
+		if let Some( waker ) = state.waker.take() {
+			waker.wake();
+		}
+	} );
+
+	Delay {
+		task: Some( task ),
+		state
+	}
 }
 
-pub fn wait_on_task<T,R>( task: T ) where
-	T: FnOnce() -> R
-{
-	let cls = Box::into_raw( Box::new( task ) );
 
 
+impl Future for Delay {
+	type Output = ();
+
+	fn poll( self: Pin<&mut Self>, cx: &mut Context<'_> ) -> Poll<()> {
+		let mut state = self.state.borrow_mut();
+
+		if state.elapsed {
+			Poll::Ready( () )
+		}
+		else {
+			state.waker = Some( cx.waker().clone() );
+			Poll::Pending
+		}
+	}
+}
+
+impl Drop for Delay {
+
+	fn drop( &mut self ) {
+		if let Some( task ) = self.task.take() {
+			task.cancel();
+		}
+	}
+}
+
+impl Task {
+
+	/// Cancels the task if it hasn't run yet.
+	/// Dropping a task without cancelling it lets it run.
+	pub fn cancel( self ) {
+		if self.closure.is_pending() {
+			unsafe {
+				GNUNET_SCHEDULER_cancel( self.inner );
+				self.closure.cancel();
+			}
+		}
+	}
 }
 
 
@@ -44,6 +132,7 @@ pub fn wait_on_task<T,R>( task: T ) where
 unsafe extern "C" fn ffi_task_callback<T>( cls: *mut c_void ) where
 	T: FnOnce()
 {
-	let task: Box<T> = Box::from_raw( cls as _ );
-	let result = task();
-}
\ No newline at end of file
+	if let Some( task ) = closure::Once::take::<T>( cls ) {
+		task();
+	}
+}
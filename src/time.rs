@@ -0,0 +1,104 @@
+use gnunet_sys::*;
+
+use std::{
+	fmt,
+	time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+
+
+/// A point in time, in microseconds since the UNIX epoch, like Gnunet's `GNUNET_TIME_Absolute`.
+/// The largest value means forever.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Absolute ( u64 );
+
+
+
+impl Absolute {
+
+	/// The point in time that never comes.
+	pub const FOREVER: Self = Self ( u64::MAX );
+	pub const ZERO: Self = Self ( 0 );
+
+	/// The point in time that is the given duration from now.
+	pub fn after( duration: Duration ) -> Self {
+		Self::now().saturating_add( duration )
+	}
+
+	/// Adds the duration, without going past forever.
+	pub fn saturating_add( self, duration: Duration ) -> Self {
+		if self.is_forever() {
+			return self
+		}
+
+		let micros = duration.as_micros();
+		if micros >= u64::MAX as u128 {
+			Self::FOREVER
+		}
+		else {
+			Self ( self.0.saturating_add( micros as u64 ) )
+		}
+	}
+
+	pub fn as_micros( &self ) -> u64 {
+		self.0
+	}
+
+	pub fn from_micros( micros: u64 ) -> Self {
+		Self ( micros )
+	}
+
+	pub(in crate) fn from_inner( inner: GNUNET_TIME_Absolute ) -> Self {
+		Self ( inner.abs_value_us )
+	}
+
+	pub fn is_forever( &self ) -> bool {
+		self.0 == u64::MAX
+	}
+
+	/// Whether this point in time has passed.
+	pub fn is_past( &self ) -> bool {
+		*self < Self::now()
+	}
+
+	pub fn now() -> Self {
+		Self::from_inner( unsafe { GNUNET_TIME_absolute_get() } )
+	}
+
+	pub(in crate) fn to_inner( &self ) -> GNUNET_TIME_Absolute {
+		GNUNET_TIME_Absolute { abs_value_us: self.0 }
+	}
+
+	/// Converts to a `SystemTime`, or `None` for forever.
+	pub fn to_system_time( &self ) -> Option<SystemTime> {
+		if self.is_forever() {
+			None
+		}
+		else {
+			UNIX_EPOCH.checked_add( Duration::from_micros( self.0 ) )
+		}
+	}
+}
+
+impl fmt::Debug for Absolute {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.is_forever() {
+			write!(f, "Absolute(forever)")
+		}
+		else {
+			write!(f, "Absolute({}us)", self.0)
+		}
+	}
+}
+
+impl From<SystemTime> for Absolute {
+
+	/// Times before the UNIX epoch become the epoch itself.
+	fn from( time: SystemTime ) -> Self {
+		match time.duration_since( UNIX_EPOCH ) {
+			Ok( duration ) => Self::ZERO.saturating_add( duration ),
+			Err(_) => Self::ZERO
+		}
+	}
+}